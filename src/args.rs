@@ -5,44 +5,84 @@ use reqwest::Url;
 
 use everygarf::{ImageFormat, Source};
 
+use crate::cache::{Compression, UrlFormat};
+use crate::progress::{ProgressStyle, Renderer, Stream, Verbosity};
+use crate::proxy::{ProxyPool, ProxyStrategy};
+
 pub mod defaults {
     use std::num::NonZero;
+    use std::time::Duration;
 
     pub const JOB_COUNT: NonZero<usize> = NonZero::new(20).unwrap();
     pub const MAX_ATTEMPTS: NonZero<usize> = NonZero::new(10).unwrap();
 
+    pub const VERIFY_SAMPLE: NonZero<usize> = NonZero::new(50).unwrap();
+
     pub const TIMEOUT: NonZero<u64> = NonZero::new(5).unwrap();
     pub const TIMEOUT_INITIAL: NonZero<u64> = NonZero::new(20).unwrap();
 
     pub const CACHE: &str = "https://raw.githubusercontent.com/dxrcy/everygarf-cache/master/cache";
+    /// Skip checking proxy servers when downloading fewer images than this.
+    pub const PING_MIN_IMAGES: usize = 10;
+
+    pub const WATCH_POLL_MIN: Duration = Duration::from_secs(60);
+    pub const WATCH_POLL_MAX: Duration = Duration::from_secs(30 * 60);
+    pub const WATCH_GIVE_UP_AFTER: NonZero<u32> = NonZero::new(24).unwrap();
+
+    #[cfg(feature = "filetype")]
+    pub const DEDUPE_MAX_DISTANCE: u32 = 4;
+
+    #[cfg(feature = "serve")]
+    pub const SERVE_LISTEN: &str = "127.0.0.1:8080";
+
     pub const PROXY: &str = "https://proxy.darcy-700.workers.dev/cors-proxy";
 
     pub const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0.0.0 Safari/537.36";
 }
 
 #[derive(clap::Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     pub directory: Option<PathBuf>,
 
     #[arg(long = "tree")]
     pub file_tree: bool,
 
+    /// Load options from this config file instead of the default
+    #[arg(long = "config", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Named profile from config file(s)
+    #[arg(short = 'k', long = "profile", global = true)]
+    pub profile: Option<String>,
+
     #[arg(short = 's', long = "start")]
     pub start_date: Option<chrono::NaiveDate>,
 
+    /// Defaults to latest comic
+    #[arg(short = 'e', long = "end")]
+    pub end_date: Option<chrono::NaiveDate>,
+
+    /// Only include dates on these days of the week
+    #[arg(short = 'w', long = "weekday")]
+    pub weekdays: Vec<chrono::Weekday>,
+
     #[arg(short = 'm', long = "max")]
     pub max_images: Option<NonZero<usize>>,
 
-    #[arg(short = 'j', long = "jobs", default_value_t = defaults::JOB_COUNT)]
+    #[arg(short = 'j', long = "jobs", global = true, default_value_t = defaults::JOB_COUNT)]
     pub job_count: NonZero<usize>,
 
-    #[arg(short = 'a', long = "attempts", default_value_t = defaults::MAX_ATTEMPTS)]
+    #[arg(short = 'a', long = "attempts", global = true, default_value_t = defaults::MAX_ATTEMPTS)]
     pub max_attempts: NonZero<usize>,
 
-    #[arg(short = 't', long = "timeout", default_value_t = defaults::TIMEOUT)]
+    #[arg(short = 't', long = "timeout", global = true, default_value_t = defaults::TIMEOUT)]
     pub timeout_primary: NonZero<u64>,
 
-    #[arg(short = 'T', long = "initial-timeout", default_value_t = defaults::TIMEOUT_INITIAL)]
+    #[arg(short = 'T', long = "initial-timeout", global = true, default_value_t = defaults::TIMEOUT_INITIAL)]
     pub timeout_initial: NonZero<u64>,
 
     #[arg(short = 'N', long = "notify-on-fail")]
@@ -51,12 +91,51 @@ pub struct Args {
     #[arg(long = "remove-all")]
     pub remove_all: bool,
 
-    #[arg(short = 'p', long = "proxy", default_value = defaults::PROXY, conflicts_with = "no_proxy")]
-    pub proxy: Url,
+    /// Shell command to run after each image is saved (repeatable)
+    #[arg(long = "on-save", global = true)]
+    pub on_save: Vec<String>,
+
+    /// Shell command to run after all images are saved (repeatable)
+    #[arg(long = "on-complete")]
+    pub on_complete: Vec<String>,
+
+    /// Url to post a JSON summary to, when run completes or fails (repeatable)
+    #[arg(long = "webhook")]
+    pub webhooks: Vec<Url>,
+
+    /// Skip dates which failed in an unfinished previous run, instead of retrying them
+    #[arg(long = "skip-failed")]
+    pub skip_failed: bool,
+
+    #[arg(short = 'p', long = "proxy", global = true, default_value = defaults::PROXY, conflicts_with = "no_proxy")]
+    pub proxy: Vec<Url>,
+
+    #[arg(
+        long = "proxy-strategy",
+        global = true,
+        ignore_case = true,
+        default_value = "failover"
+    )]
+    pub proxy_strategy: ProxyStrategy,
 
-    #[arg(short = 'P', long = "no-proxy", conflicts_with = "proxy")]
+    #[arg(
+        short = 'P',
+        long = "no-proxy",
+        global = true,
+        conflicts_with = "proxy"
+    )]
     pub no_proxy: bool,
 
+    /// Forward proxy (`http://`, `https://`, `socks5://` or `socks5h://`) for all requests.
+    /// Independent of `--proxy`, which is a url prefix
+    #[arg(short = 'x', long = "forward-proxy", global = true)]
+    pub forward_proxy: Option<Url>,
+
+    /// Ignore `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY` environment variables
+    #[arg(long = "no-env-proxy", global = true)]
+    pub no_env_proxy: bool,
+
+    /// Check proxy servers before downloading, even for a small number of images
     #[arg(long = "always-ping")]
     pub always_ping: bool,
 
@@ -69,15 +148,230 @@ pub struct Args {
     #[arg(long = "save-cache")]
     pub save_cache: Option<PathBuf>,
 
-    #[arg(short = 'u', long = "user-agent", default_value_t = defaults::USER_AGENT.to_string())]
+    #[arg(long = "cache-compression", ignore_case = true, default_value = "none")]
+    pub cache_compression: Compression,
+
+    #[arg(short = 'u', long = "user-agent", global = true, default_value_t = defaults::USER_AGENT.to_string())]
     pub user_agent: String,
 
     #[arg(short = 'S', long = "source", requires = "no_cache", default_value_t = Default::default())]
     pub source: Source,
 
-    #[arg(short = 'f', long = "format", ignore_case = true, default_value_t = Default::default())]
+    #[arg(short = 'f', long = "format", global = true, ignore_case = true, default_value_t = Default::default())]
     pub image_format: ImageFormat,
 
+    #[arg(
+        long = "progress",
+        global = true,
+        ignore_case = true,
+        default_value = "auto"
+    )]
+    pub progress: ProgressStyle,
+
+    /// Only print errors
+    #[arg(long = "quiet", global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print every update
+    #[arg(short = 'v', long = "verbose", global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Append debug logs to this file (filtered by `RUST_LOG`, if set)
+    #[arg(long = "log-file", global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Serve Prometheus metrics at `http://<address>/metrics` while running
+    #[cfg(feature = "metrics")]
+    #[arg(long = "metrics-listen", global = true)]
+    pub metrics_listen: Option<std::net::SocketAddr>,
+
+    /// Full-screen interactive display, with a calendar of all dates
+    #[cfg(feature = "tui")]
+    #[arg(long = "tui", conflicts_with_all = ["query", "resolve_only", "progress"])]
+    pub tui: bool,
+
     #[arg(short = 'q', long = "query")]
     pub query: bool,
+
+    /// Only resolve image urls, without downloading images
+    #[arg(short = 'r', long = "resolve-only")]
+    pub resolve_only: bool,
+
+    #[arg(
+        long = "resolve-format",
+        ignore_case = true,
+        default_value = "csv",
+        requires = "resolve_only"
+    )]
+    pub resolve_format: UrlFormat,
+
+    /// Write resolved urls here instead of stdout
+    #[arg(short = 'o', long = "output", requires = "resolve_only")]
+    pub output: Option<PathBuf>,
+}
+
+impl Args {
+    pub fn verbosity(&self) -> Verbosity {
+        if self.quiet {
+            Verbosity::Quiet
+        } else if self.verbose > 0 {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
+    }
+
+    /// Queries and resolve-only runs do not write to target directory.
+    pub fn saves_images(&self) -> bool {
+        !self.resolve_only && !self.query
+    }
+
+    /// Resolved urls are written to stdout, unless written to a file.
+    pub fn writes_urls_to_stdout(&self) -> bool {
+        self.resolve_only && self.output.is_none()
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        let stream = if self.writes_urls_to_stdout() {
+            Stream::Stderr
+        } else {
+            Stream::Stdout
+        };
+        Renderer::new(self.progress, self.verbosity(), stream)
+    }
+
+    pub fn proxy_pool(&self) -> ProxyPool {
+        if self.no_proxy {
+            return ProxyPool::direct();
+        }
+        ProxyPool::new(self.proxy.clone(), self.proxy_strategy)
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Maintain a url cache file
+    #[command(subcommand)]
+    Cache(CacheCommand),
+
+    /// Keep running, and download each new comic once it is published
+    Watch {
+        directory: Option<PathBuf>,
+
+        /// Hours after expected publish time to stop polling for a comic, and move to the next date
+        #[arg(long = "give-up-after", value_name = "HOURS", default_value_t = defaults::WATCH_GIVE_UP_AFTER)]
+        give_up_after: NonZero<u32>,
+    },
+
+    /// Print path of the comic of this day in a random past year
+    Today(PickArgs),
+
+    /// Print path of a random comic
+    Random {
+        #[arg(short = 's', long = "start")]
+        start_date: Option<chrono::NaiveDate>,
+
+        #[arg(short = 'e', long = "end")]
+        end_date: Option<chrono::NaiveDate>,
+
+        /// Only include dates on these days of the week
+        #[arg(short = 'w', long = "weekday")]
+        weekdays: Vec<chrono::Weekday>,
+
+        #[command(flatten)]
+        pick: PickArgs,
+    },
+
+    /// Print path of the comic of a date
+    Show {
+        date: chrono::NaiveDate,
+
+        #[command(flatten)]
+        pick: PickArgs,
+    },
+
+    /// Find dates with the same strip saved, and queue them to be downloaded again
+    #[cfg(feature = "filetype")]
+    Dedupe {
+        directory: Option<PathBuf>,
+
+        /// Most bits of perceptual hash which may differ between near-identical images
+        #[arg(short = 'm', long = "max-distance", default_value_t = defaults::DEDUPE_MAX_DISTANCE)]
+        max_distance: u32,
+
+        /// Move identical images aside, so the next run downloads them again
+        #[arg(long = "queue")]
+        queue: bool,
+
+        /// Also move near-identical images aside, which may be different strips
+        #[arg(long = "queue-similar", requires = "queue")]
+        queue_similar: bool,
+    },
+
+    /// Serve saved images over HTTP, with a JSON API and a page to browse them
+    #[cfg(feature = "serve")]
+    Serve {
+        directory: Option<PathBuf>,
+
+        #[arg(short = 'l', long = "listen", default_value = defaults::SERVE_LISTEN)]
+        listen: std::net::SocketAddr,
+    },
+}
+
+/// Comic is fetched into the target directory if it is not saved yet.
+#[derive(clap::Args)]
+pub struct PickArgs {
+    pub directory: Option<PathBuf>,
+
+    /// Also open image with the system viewer
+    #[arg(long = "open")]
+    pub open: bool,
+
+    /// Also draw image in the terminal
+    #[cfg(feature = "filetype")]
+    #[arg(
+        short = 'i',
+        long = "inline",
+        value_name = "PROTOCOL",
+        num_args = 0..=1,
+        default_missing_value = "auto",
+        require_equals = true,
+        ignore_case = true
+    )]
+    pub inline: Option<crate::terminal_image::Protocol>,
+}
+
+impl Command {
+    /// Only prints a path, which may be read by scripts.
+    pub fn prints_path(&self) -> bool {
+        matches!(
+            self,
+            Self::Today(_) | Self::Random { .. } | Self::Show { .. }
+        )
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum CacheCommand {
+    /// Resolve image urls for all dates after the last entry, and merge them into the file
+    Update {
+        file: PathBuf,
+
+        /// Write merged cache here instead of overwriting the input file
+        #[arg(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+
+        /// Defaults to the compression of the input file
+        #[arg(long = "compression", ignore_case = true)]
+        compression: Option<Compression>,
+    },
+
+    /// Check that a random sample of image urls are reachable
+    Verify {
+        /// Local path or remote url
+        file: PathBuf,
+
+        #[arg(short = 'n', long = "sample", default_value_t = defaults::VERIFY_SAMPLE)]
+        sample: NonZero<usize>,
+    },
 }
@@ -0,0 +1,388 @@
+//! Self-hostable CORS proxy, implementing the same `{proxy}?{url}` protocol as the default
+//! proxy server.
+
+#![allow(clippy::uninlined_format_args)]
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZero;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, RawQuery, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use clap::Parser;
+use reqwest::{Client, Url};
+
+mod defaults {
+    use std::num::NonZero;
+
+    pub const LISTEN: &str = "127.0.0.1:8787";
+    pub const ALLOWED_HOSTS: &[&str] = &[
+        "gocomics.com",
+        "www.gocomics.com",
+        "featureassets.gocomics.com",
+    ];
+
+    pub const CACHE_TTL: u64 = 60 * 60;
+    pub const CACHE_SIZE: usize = 64;
+    pub const MAX_REDIRECTS: usize = 10;
+
+    pub const RATE_LIMIT: NonZero<u32> = NonZero::new(120).unwrap();
+    pub const TIMEOUT: NonZero<u64> = NonZero::new(10).unwrap();
+}
+
+#[derive(clap::Parser)]
+struct Args {
+    #[arg(short = 'l', long = "listen", default_value = defaults::LISTEN)]
+    listen: SocketAddr,
+
+    /// Hosts which may be requested through the proxy (replaces default list)
+    #[arg(short = 'a', long = "allow-host")]
+    allowed_hosts: Vec<String>,
+
+    /// Seconds to keep successful responses (0 to disable caching)
+    #[arg(long = "cache-ttl", default_value_t = defaults::CACHE_TTL)]
+    cache_ttl: u64,
+
+    /// Maximum total size of cached response bodies, in megabytes
+    #[arg(long = "cache-size", value_name = "MEGABYTES", default_value_t = defaults::CACHE_SIZE)]
+    cache_size: usize,
+
+    /// Maximum requests per minute, per client address
+    #[arg(short = 'r', long = "rate-limit", default_value_t = defaults::RATE_LIMIT)]
+    rate_limit: NonZero<u32>,
+
+    #[arg(short = 't', long = "timeout", default_value_t = defaults::TIMEOUT)]
+    timeout: NonZero<u64>,
+}
+
+struct ProxyState {
+    client: Client,
+    allowed_hosts: Arc<[String]>,
+    cache: Mutex<ResponseCache>,
+    limiter: Mutex<RateLimiter>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let allowed_hosts: Arc<[String]> = if args.allowed_hosts.is_empty() {
+        defaults::ALLOWED_HOSTS
+            .iter()
+            .map(|host| host.to_string())
+            .collect()
+    } else {
+        args.allowed_hosts.into()
+    };
+
+    // Every hop must be allowed, otherwise an allowed host could redirect anywhere
+    let redirect_hosts = allowed_hosts.clone();
+    let redirect_policy = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= defaults::MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if is_allowed(attempt.url(), &redirect_hosts) {
+            attempt.follow()
+        } else {
+            attempt.error("redirect to host which is not allowed")
+        }
+    });
+
+    let client = Client::builder()
+        .timeout(Duration::from_secs(args.timeout.into()))
+        .redirect(redirect_policy)
+        .build()
+        .expect("Failed to build request client. This error should never occur.");
+
+    let state = Arc::new(ProxyState {
+        client,
+        allowed_hosts,
+        cache: Mutex::new(ResponseCache::new(
+            Duration::from_secs(args.cache_ttl),
+            args.cache_size.saturating_mul(1_000_000),
+        )),
+        limiter: Mutex::new(RateLimiter::new(args.rate_limit)),
+    });
+
+    // Any path is accepted, so the proxy can be mounted at any prefix (such as `/cors-proxy`)
+    let app = Router::new().fallback(handle_request).with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("failed to bind to {}", args.listen))?;
+    println!("listening on http://{}", args.listen);
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .with_context(|| "server failed")
+}
+
+async fn handle_request(
+    State(state): State<Arc<ProxyState>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    // Request without target is a health check
+    let Some(query) = query.filter(|query| !query.is_empty()) else {
+        return with_cors((StatusCode::OK, "ok").into_response());
+    };
+
+    if !state.limiter.lock().unwrap().allow(address.ip()) {
+        return error_response(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded");
+    }
+
+    let Ok(url) = Url::parse(&query) else {
+        return error_response(StatusCode::BAD_REQUEST, "invalid target url");
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return error_response(StatusCode::BAD_REQUEST, "unsupported url scheme");
+    }
+    if !is_allowed(&url, &state.allowed_hosts) {
+        return error_response(StatusCode::FORBIDDEN, "host not allowed");
+    }
+
+    if let Some(cached) = state.cache.lock().unwrap().get(url.as_str()) {
+        return cached.to_response();
+    }
+
+    let mut request = state.client.get(url.clone());
+    if let Some(user_agent) = headers.get(header::USER_AGENT) {
+        request = request.header(header::USER_AGENT, user_agent);
+    }
+
+    let upstream = match fetch_upstream(request).await {
+        Ok(upstream) => upstream,
+        Err(error) => {
+            eprintln!("{} | {:#}", url, error);
+            return error_response(StatusCode::BAD_GATEWAY, "failed to reach upstream");
+        }
+    };
+
+    if upstream.status.is_success() {
+        state
+            .cache
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), upstream.clone());
+    }
+    upstream.to_response()
+}
+
+fn is_allowed(url: &Url, allowed_hosts: &[String]) -> bool {
+    matches!(url.scheme(), "http" | "https")
+        && url
+            .host_str()
+            .is_some_and(|host| allowed_hosts.iter().any(|allowed| allowed == host))
+}
+
+#[derive(Clone)]
+struct UpstreamResponse {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+}
+
+async fn fetch_upstream(request: reqwest::RequestBuilder) -> Result<UpstreamResponse> {
+    let response = request.send().await.with_context(|| "sending request")?;
+    let status = StatusCode::from_u16(response.status().as_u16())
+        .with_context(|| "invalid upstream status")?;
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| HeaderValue::from_bytes(value.as_bytes()).ok());
+    let body = response.bytes().await.with_context(|| "reading body")?;
+    Ok(UpstreamResponse {
+        status,
+        content_type,
+        body,
+    })
+}
+
+impl UpstreamResponse {
+    fn to_response(&self) -> Response {
+        let mut response = (self.status, self.body.clone()).into_response();
+        if let Some(content_type) = &self.content_type {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, content_type.clone());
+        }
+        with_cors(response)
+    }
+}
+
+fn error_response(status: StatusCode, message: &'static str) -> Response {
+    with_cors((status, message).into_response())
+}
+
+fn with_cors(mut response: Response) -> Response {
+    response.headers_mut().insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    response
+}
+
+/// Bounded by total size of response bodies.
+struct ResponseCache {
+    ttl: Duration,
+    max_bytes: usize,
+    total_bytes: usize,
+    entries: HashMap<String, (Instant, UpstreamResponse)>,
+}
+
+impl ResponseCache {
+    fn new(ttl: Duration, max_bytes: usize) -> Self {
+        Self {
+            ttl,
+            max_bytes,
+            total_bytes: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, url: &str) -> Option<UpstreamResponse> {
+        let (inserted, response) = self.entries.get(url)?;
+        if inserted.elapsed() > self.ttl {
+            self.remove(url);
+            return None;
+        }
+        Some(response.clone())
+    }
+
+    fn insert(&mut self, url: String, response: UpstreamResponse) {
+        let size = response.body.len();
+        if self.ttl.is_zero() || size > self.max_bytes {
+            return;
+        }
+        self.remove(&url);
+        if self.total_bytes + size > self.max_bytes {
+            let ttl = self.ttl;
+            self.entries.retain(|_, (inserted, response)| {
+                let is_fresh = inserted.elapsed() <= ttl;
+                if !is_fresh {
+                    self.total_bytes -= response.body.len();
+                }
+                is_fresh
+            });
+        }
+        // Still full of fresh entries: evict oldest
+        while self.total_bytes + size > self.max_bytes
+            && let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (inserted, _))| *inserted)
+                .map(|(url, _)| url.clone())
+        {
+            self.remove(&oldest);
+        }
+        self.total_bytes += size;
+        self.entries.insert(url, (Instant::now(), response));
+    }
+
+    fn remove(&mut self, url: &str) {
+        if let Some((_, response)) = self.entries.remove(url) {
+            self.total_bytes -= response.body.len();
+        }
+    }
+}
+
+/// Token bucket per client address, refilled continuously.
+struct RateLimiter {
+    per_minute: f64,
+    buckets: HashMap<IpAddr, (Instant, f64)>,
+}
+
+impl RateLimiter {
+    fn new(per_minute: NonZero<u32>) -> Self {
+        Self {
+            per_minute: per_minute.get().into(),
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn allow(&mut self, address: IpAddr) -> bool {
+        let now = Instant::now();
+        let per_minute = self.per_minute;
+
+        // Full buckets are equivalent to missing ones
+        if self.buckets.len() > 10_000 {
+            self.buckets.retain(|_, (updated, tokens)| {
+                *tokens + updated.elapsed().as_secs_f64() * per_minute / 60.0 < per_minute
+            });
+        }
+
+        let (updated, tokens) = self.buckets.entry(address).or_insert((now, per_minute));
+        let refill = now.duration_since(*updated).as_secs_f64() * per_minute / 60.0;
+        *tokens = (*tokens + refill).min(per_minute);
+        *updated = now;
+
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(size: usize) -> UpstreamResponse {
+        UpstreamResponse {
+            status: StatusCode::OK,
+            content_type: None,
+            body: Bytes::from(vec![0; size]),
+        }
+    }
+
+    #[test]
+    fn cache_evicts_oldest_when_over_size() {
+        let mut cache = ResponseCache::new(Duration::from_secs(60), 100);
+        cache.insert("a".into(), response(40));
+        cache.insert("b".into(), response(40));
+        cache.insert("c".into(), response(40));
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.total_bytes, 80);
+    }
+
+    #[test]
+    fn cache_skips_bodies_larger_than_size() {
+        let mut cache = ResponseCache::new(Duration::from_secs(60), 100);
+        cache.insert("a".into(), response(40));
+        cache.insert("b".into(), response(101));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.total_bytes, 40);
+    }
+
+    #[test]
+    fn cache_replaces_entry_of_same_url() {
+        let mut cache = ResponseCache::new(Duration::from_secs(60), 100);
+        cache.insert("a".into(), response(40));
+        cache.insert("a".into(), response(60));
+        assert_eq!(cache.get("a").unwrap().body.len(), 60);
+        assert_eq!(cache.total_bytes, 60);
+    }
+
+    #[test]
+    fn only_allowed_hosts_and_schemes() {
+        let allowed = ["gocomics.com".to_string()];
+        let check = |url: &str| is_allowed(&Url::parse(url).unwrap(), &allowed);
+        assert!(check("https://gocomics.com/garfield/2000/01/01"));
+        assert!(!check("https://www.gocomics.com/"));
+        assert!(!check("http://127.0.0.1/"));
+        assert!(!check("file://gocomics.com/etc/passwd"));
+    }
+}
@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read as _, Write as _};
+use std::path::PathBuf;
+
+use anyhow::{Context as _, Result, bail};
+use chrono::{NaiveDate, Utc};
+use clap::ValueEnum;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::download::IMAGE_URL_PREFIX;
+use crate::io::{get_cache_directory, write_atomic};
+
+pub type CacheData = HashMap<NaiveDate, Url>;
+
+/// First line of a versioned cache file. Files without this line are legacy (version 1) caches.
+const MAGIC: &str = "everygarf-cache";
+/// Current version written by [`encode_cache`].
+pub const FORMAT_VERSION: u32 = 2;
+/// Separates header from entries in a versioned cache file.
+const HEADER_END: &str = "---";
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+pub const STRIP: &str = "garfield";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Output format for resolved image urls.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum UrlFormat {
+    #[default]
+    Csv,
+    Json,
+    Cache,
+}
+
+pub struct Cache {
+    /// Missing for legacy (version 1) caches.
+    pub header: Option<CacheHeader>,
+    pub entries: CacheData,
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheHeader {
+    pub version: u32,
+    pub generated: NaiveDate,
+    pub strip: String,
+    pub count: usize,
+    pub checksum: u32,
+}
+
+/// Local copy of a remote cache file, with validators for conditional requests.
+pub struct StoredCache {
+    pub bytes: Vec<u8>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Decompress (if necessary) and parse a cache file of any supported format.
+pub fn parse_cached_urls(bytes: &[u8]) -> Result<Cache> {
+    let bytes = decompress(bytes)?;
+    let text = std::str::from_utf8(&bytes).with_context(|| "cache file is not valid utf-8")?;
+
+    if !text.starts_with(MAGIC) {
+        return Ok(Cache {
+            header: None,
+            entries: parse_entries(text.lines())?,
+        });
+    }
+
+    let mut lines = text.lines();
+    let header = parse_header(&mut lines).with_context(|| "malformed cache header")?;
+    if header.version > FORMAT_VERSION {
+        bail!(
+            "unsupported cache format version {} (latest supported is {})",
+            header.version,
+            FORMAT_VERSION,
+        );
+    }
+    if header.strip != STRIP {
+        bail!(
+            "cache is for strip `{}`, expected `{}`",
+            header.strip,
+            STRIP
+        );
+    }
+
+    let body: Vec<&str> = lines
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    let checksum = checksum_lines(&body);
+    if checksum != header.checksum {
+        bail!(
+            "cache checksum mismatch (expected {:08x}, found {:08x}); file is corrupted",
+            header.checksum,
+            checksum,
+        );
+    }
+
+    let entries = parse_entries(body.into_iter())?;
+    if entries.len() != header.count {
+        bail!(
+            "cache entry count mismatch (expected {}, found {}); file is corrupted",
+            header.count,
+            entries.len(),
+        );
+    }
+    Ok(Cache {
+        header: Some(header),
+        entries,
+    })
+}
+
+/// Compression of a (possibly compressed) cache file.
+pub fn detect_compression(bytes: &[u8]) -> Compression {
+    if bytes.starts_with(GZIP_MAGIC) {
+        Compression::Gzip
+    } else if bytes.starts_with(ZSTD_MAGIC) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    }
+}
+
+/// Serialize entries as a versioned cache file, sorted by date.
+pub fn encode_cache(entries: &CacheData, compression: Compression) -> Result<Vec<u8>> {
+    let mut dates: Vec<&NaiveDate> = entries.keys().collect();
+    dates.sort();
+
+    let body: Vec<String> = dates
+        .into_iter()
+        .map(|date| {
+            let url = entries[date].as_str();
+            let path = url.strip_prefix(IMAGE_URL_PREFIX).unwrap_or(url);
+            format!("{} {}", date.format("%Y-%m-%d"), path)
+        })
+        .collect();
+
+    let mut text = String::new();
+    text += &format!("{} {}\n", MAGIC, FORMAT_VERSION);
+    text += &format!("generated {}\n", Utc::now().date_naive().format("%Y-%m-%d"));
+    text += &format!("strip {}\n", STRIP);
+    text += &format!("count {}\n", body.len());
+    text += &format!("checksum {:08x}\n", checksum_lines(&body));
+    text += HEADER_END;
+    text += "\n";
+    for line in &body {
+        text += line;
+        text += "\n";
+    }
+
+    compress(text.into_bytes(), compression)
+}
+
+/// Returns `None` if no copy is stored, or it cannot be read.
+pub fn load_stored_cache(url: &Url) -> Option<StoredCache> {
+    let (data_path, meta_path) = get_stored_cache_paths(url)?;
+    let bytes = fs::read(data_path).ok()?;
+    let meta = fs::read_to_string(meta_path).unwrap_or_default();
+
+    let mut etag = None;
+    let mut last_modified = None;
+    for line in meta.lines() {
+        let Some((key, value)) = split_columns(line) else {
+            continue;
+        };
+        match key {
+            "etag" => etag = Some(value.trim().to_string()),
+            "last-modified" => last_modified = Some(value.trim().to_string()),
+            _ => (),
+        }
+    }
+
+    Some(StoredCache {
+        bytes,
+        etag,
+        last_modified,
+    })
+}
+
+pub fn store_cache(url: &Url, stored: &StoredCache) -> Result<()> {
+    let (data_path, meta_path) =
+        get_stored_cache_paths(url).with_context(|| "no cache directory for this platform")?;
+    if let Some(parent) = data_path.parent() {
+        fs::create_dir_all(parent).with_context(|| "creating cache directory")?;
+    }
+
+    let mut meta = format!("url {}\n", url);
+    if let Some(etag) = &stored.etag {
+        meta += &format!("etag {}\n", etag);
+    }
+    if let Some(last_modified) = &stored.last_modified {
+        meta += &format!("last-modified {}\n", last_modified);
+    }
+
+    write_atomic(data_path, &stored.bytes).with_context(|| "writing stored cache")?;
+    write_atomic(meta_path, meta).with_context(|| "writing stored cache metadata")?;
+    Ok(())
+}
+
+/// Copies are named after a hash of the url, so different remote caches do not collide.
+fn get_stored_cache_paths(url: &Url) -> Option<(PathBuf, PathBuf)> {
+    let name = format!("cache-{:08x}", crc32fast::hash(url.as_str().as_bytes()));
+    let directory = get_cache_directory()?;
+    Some((directory.join(&name), directory.join(name + ".meta")))
+}
+
+/// Serialize entries in any output format, sorted by date.
+/// Compression only applies to cache format.
+pub fn encode_urls(
+    entries: &CacheData,
+    format: UrlFormat,
+    compression: Compression,
+) -> Result<Vec<u8>> {
+    let mut dates: Vec<&NaiveDate> = entries.keys().collect();
+    dates.sort();
+
+    match format {
+        UrlFormat::Cache => encode_cache(entries, compression),
+        UrlFormat::Csv => {
+            let mut text = String::from("date,url\n");
+            for date in dates {
+                text += &format!("{},{}\n", date.format("%Y-%m-%d"), entries[date]);
+            }
+            Ok(text.into_bytes())
+        }
+        UrlFormat::Json => {
+            let map: serde_json::Map<String, serde_json::Value> = dates
+                .into_iter()
+                .map(|date| {
+                    let key = date.format("%Y-%m-%d").to_string();
+                    (key, entries[date].as_str().into())
+                })
+                .collect();
+            let mut bytes = serde_json::to_vec_pretty(&map)?;
+            bytes.push(b'\n');
+            Ok(bytes)
+        }
+    }
+}
+
+fn parse_header<'a>(lines: &mut impl Iterator<Item = &'a str>) -> Result<CacheHeader> {
+    let mut version = None;
+    let mut generated = None;
+    let mut strip = None;
+    let mut count = None;
+    let mut checksum = None;
+
+    for line in lines.by_ref() {
+        let line = line.trim();
+        if line == HEADER_END {
+            break;
+        }
+        let (key, value) = split_columns(line).with_context(|| "header line has no value")?;
+        let value = value.trim();
+        match key {
+            MAGIC => version = Some(value.parse().with_context(|| "invalid version")?),
+            "generated" => {
+                generated = Some(
+                    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                        .with_context(|| "invalid generation date")?,
+                )
+            }
+            "strip" => strip = Some(value.to_string()),
+            "count" => count = Some(value.parse().with_context(|| "invalid entry count")?),
+            "checksum" => {
+                checksum = Some(u32::from_str_radix(value, 16).with_context(|| "invalid checksum")?)
+            }
+            // Ignore unknown keys for forwards compatibility
+            _ => (),
+        }
+    }
+
+    Ok(CacheHeader {
+        version: version.with_context(|| "missing version")?,
+        generated: generated.with_context(|| "missing generation date")?,
+        strip: strip.with_context(|| "missing strip name")?,
+        count: count.with_context(|| "missing entry count")?,
+        checksum: checksum.with_context(|| "missing checksum")?,
+    })
+}
+
+fn parse_entries<'a>(lines: impl Iterator<Item = &'a str>) -> Result<CacheData> {
+    let mut entries = CacheData::new();
+    for (number, line) in lines.enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (date_string, url_path) =
+            split_columns(line).with_context(|| format!("missing url on line {}", number + 1))?;
+        let date = NaiveDate::parse_from_str(date_string, "%Y-%m-%d")
+            .with_context(|| format!("invalid date on line {}", number + 1))?;
+        let image_url = expand_image_url(url_path.trim())
+            .with_context(|| format!("invalid url path on line {}", number + 1))?;
+        entries.insert(date, image_url);
+    }
+    Ok(entries)
+}
+
+fn checksum_lines(lines: &[impl AsRef<str>]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for line in lines {
+        hasher.update(line.as_ref().as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.finalize()
+}
+
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>> {
+    match detect_compression(bytes) {
+        Compression::None => Ok(bytes.to_vec()),
+        Compression::Gzip => {
+            let mut output = Vec::new();
+            flate2::read::GzDecoder::new(bytes)
+                .read_to_end(&mut output)
+                .with_context(|| "decompressing gzip cache")?;
+            Ok(output)
+        }
+        Compression::Zstd => zstd::decode_all(bytes).with_context(|| "decompressing zstd cache"),
+    }
+}
+
+fn compress(bytes: Vec<u8>, compression: Compression) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(bytes),
+        Compression::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(&bytes)?;
+            encoder.finish().with_context(|| "compressing gzip cache")
+        }
+        Compression::Zstd => {
+            zstd::encode_all(bytes.as_slice(), 19).with_context(|| "compressing zstd cache")
+        }
+    }
+}
+
+fn split_columns(line: &str) -> Option<(&str, &str)> {
+    Some(line.split_at(line.find(' ')?))
+}
+
+fn expand_image_url(url_path: &str) -> Option<Url> {
+    // Assumes base url is well-formed. The only parsing error should be a malformed path
+    let url = IMAGE_URL_PREFIX.to_string() + url_path;
+    Url::parse(&url).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_entries() -> CacheData {
+        [
+            ("1978-06-19", "aaaa"),
+            ("1978-06-20", "bbbb"),
+            ("2000-01-01", "cccc"),
+        ]
+        .into_iter()
+        .map(|(date, path)| (date.parse().unwrap(), expand_image_url(path).unwrap()))
+        .collect()
+    }
+
+    fn encode_text(entries: &CacheData) -> String {
+        String::from_utf8(encode_cache(entries, Compression::None).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trip_all_compressions() {
+        let entries = sample_entries();
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let bytes = encode_cache(&entries, compression).unwrap();
+            assert_eq!(detect_compression(&bytes), compression);
+            let cache = parse_cached_urls(&bytes).unwrap();
+            assert_eq!(cache.entries, entries);
+            let header = cache.header.unwrap();
+            assert_eq!(header.version, FORMAT_VERSION);
+            assert_eq!(header.strip, STRIP);
+            assert_eq!(header.count, entries.len());
+        }
+    }
+
+    #[test]
+    fn parse_legacy_format() {
+        let cache = parse_cached_urls(b"1978-06-19 aaaa\n\n1978-06-20 bbbb\n").unwrap();
+        assert!(cache.header.is_none());
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(
+            cache.entries[&"1978-06-20".parse().unwrap()],
+            expand_image_url("bbbb").unwrap()
+        );
+    }
+
+    #[test]
+    fn reject_checksum_mismatch() {
+        let text = encode_text(&sample_entries()).replace("bbbb", "bbbc");
+        let error = parse_cached_urls(text.as_bytes()).err().unwrap();
+        assert!(error.to_string().contains("checksum"));
+    }
+
+    #[test]
+    fn reject_truncated_file() {
+        let text = encode_text(&sample_entries());
+        let truncated = &text[..text.trim_end().rfind('\n').unwrap() + 1];
+        assert!(parse_cached_urls(truncated.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn reject_count_mismatch() {
+        // Checksum is still valid, as the body is unchanged
+        let text = encode_text(&sample_entries()).replace("count 3", "count 4");
+        let error = parse_cached_urls(text.as_bytes()).err().unwrap();
+        assert!(error.to_string().contains("count"));
+    }
+
+    #[test]
+    fn reject_newer_version_and_other_strip() {
+        let text = encode_text(&sample_entries());
+        let newer = text.replacen(
+            &format!("{} {}", MAGIC, FORMAT_VERSION),
+            &format!("{} {}", MAGIC, FORMAT_VERSION + 1),
+            1,
+        );
+        assert!(parse_cached_urls(newer.as_bytes()).is_err());
+        let other_strip = text.replace("strip garfield", "strip peanuts");
+        assert!(parse_cached_urls(other_strip.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn header_ignores_unknown_keys() {
+        let text = encode_text(&sample_entries())
+            .replace("strip garfield\n", "strip garfield\nfuture 1\n");
+        assert!(parse_cached_urls(text.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn header_requires_all_keys() {
+        let text = encode_text(&sample_entries());
+        let without_checksum: String = text
+            .lines()
+            .filter(|line| !line.starts_with("checksum"))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let error = parse_cached_urls(without_checksum.as_bytes())
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", error).contains("missing checksum"));
+    }
+
+    #[test]
+    fn detect_uncompressed() {
+        assert_eq!(
+            detect_compression(b"everygarf-cache 2\n"),
+            Compression::None
+        );
+        assert_eq!(detect_compression(b""), Compression::None);
+    }
+
+    #[test]
+    fn encode_urls_formats() {
+        let entries = sample_entries();
+
+        let csv = encode_urls(&entries, UrlFormat::Csv, Compression::None).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("date,url"));
+        let parsed: CacheData = lines
+            .map(|line| {
+                let (date, url) = line.split_once(',').unwrap();
+                (date.parse().unwrap(), Url::parse(url).unwrap())
+            })
+            .collect();
+        assert_eq!(parsed, entries);
+
+        let json = encode_urls(&entries, UrlFormat::Json, Compression::None).unwrap();
+        let parsed: HashMap<NaiveDate, Url> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed, entries);
+
+        let cache = encode_urls(&entries, UrlFormat::Cache, Compression::Zstd).unwrap();
+        assert_eq!(parse_cached_urls(&cache).unwrap().entries, entries);
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use std::fs;
+use std::num::NonZero;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result, bail};
+use chrono::{NaiveDate, Weekday};
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use everygarf::ImageFormat;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::args::Args;
+use crate::cache::Compression;
+use crate::io::get_target_directory;
+use crate::progress::ProgressStyle;
+use crate::proxy::ProxyStrategy;
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Config file, found in user config directory and in `.everygarf` of target directory.
+#[derive(Default)]
+struct Config {
+    options: ConfigOptions,
+    profiles: HashMap<String, ConfigOptions>,
+}
+
+/// Keys are named after the corresponding long command line option.
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigOptions {
+    directory: Option<PathBuf>,
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+    max: Option<NonZero<usize>>,
+    skip_failed: Option<bool>,
+    on_save: Option<Vec<String>>,
+    on_complete: Option<Vec<String>>,
+    webhook: Option<Vec<Url>>,
+    log_file: Option<PathBuf>,
+    // Plural key is accepted, as the option takes multiple values
+    #[serde(alias = "weekdays")]
+    weekday: Option<Vec<Weekday>>,
+    format: Option<ImageFormat>,
+    jobs: Option<NonZero<usize>>,
+    attempts: Option<NonZero<usize>>,
+    timeout: Option<NonZero<u64>>,
+    initial_timeout: Option<NonZero<u64>>,
+    proxy: Option<Vec<Url>>,
+    no_proxy: Option<bool>,
+    proxy_strategy: Option<ProxyStrategy>,
+    forward_proxy: Option<Url>,
+    no_env_proxy: Option<bool>,
+    always_ping: Option<bool>,
+    cache: Option<PathBuf>,
+    no_cache: Option<bool>,
+    save_cache: Option<PathBuf>,
+    cache_compression: Option<Compression>,
+    user_agent: Option<String>,
+    progress: Option<ProgressStyle>,
+}
+
+/// Apply options from config files, for any options not given on the command line.
+///
+/// Options are taken from (lowest to highest priority): user config, target directory config,
+/// user config profile, target directory config profile. A profile is chosen explicitly, so it
+/// takes precedence over either config without one.
+pub fn apply_config_files(args: &mut Args, matches: &ArgMatches) -> Result<()> {
+    let user_config_path = match &args.config {
+        Some(path) => Some(path.clone()),
+        None => get_user_config_path(),
+    };
+    let user_config = match &user_config_path {
+        Some(path) => load_config(path, args.config.is_some())
+            .with_context(|| format!("failed to load config file {}", path.display()))?,
+        None => Config::default(),
+    };
+
+    let user_profile = match &args.profile {
+        Some(profile) => user_config.profiles.get(profile).cloned(),
+        None => None,
+    };
+    let mut found_profile = args.profile.is_none() || user_profile.is_some();
+
+    // Target directory is needed to find the target config file
+    let directory = args
+        .directory
+        .clone()
+        .or_else(|| user_profile.as_ref()?.directory.clone())
+        .or_else(|| user_config.options.directory.clone())
+        .or_else(get_target_directory);
+
+    let target_config = match &directory {
+        Some(directory) => {
+            let path = get_target_config_path(directory);
+            load_config(&path, false)
+                .with_context(|| format!("failed to load config file {}", path.display()))?
+        }
+        None => Config::default(),
+    };
+    let target_profile = match &args.profile {
+        Some(profile) => target_config.profiles.get(profile).cloned(),
+        None => None,
+    };
+    found_profile |= target_profile.is_some();
+
+    let mut options = user_config.options;
+    // Directory cannot be changed from inside itself
+    options = options.merge(ConfigOptions {
+        directory: None,
+        ..target_config.options
+    });
+    if let Some(user_profile) = user_profile {
+        options = options.merge(user_profile);
+    }
+    if let Some(target_profile) = target_profile {
+        options = options.merge(ConfigOptions {
+            directory: None,
+            ..target_profile
+        });
+    }
+
+    if !found_profile {
+        bail!(
+            "profile `{}` not found in any config file",
+            args.profile.as_deref().unwrap_or_default(),
+        );
+    }
+
+    options.apply(args, matches);
+    Ok(())
+}
+
+pub fn get_target_config_path(directory: impl AsRef<Path>) -> PathBuf {
+    directory
+        .as_ref()
+        .join(crate::io::DATA_DIRECTORY_NAME)
+        .join(CONFIG_FILE_NAME)
+}
+
+fn get_user_config_path() -> Option<PathBuf> {
+    Some(
+        dirs_next::config_dir()?
+            .join("everygarf")
+            .join(CONFIG_FILE_NAME),
+    )
+}
+
+/// Missing file is treated as empty config, unless `required`.
+fn load_config(path: impl AsRef<Path>, required: bool) -> Result<Config> {
+    let path = path.as_ref();
+    if !required && !path.exists() {
+        return Ok(Config::default());
+    }
+    let text = fs::read_to_string(path).with_context(|| "reading file")?;
+    let mut table: toml::Table = toml::from_str(&text).with_context(|| "parsing file")?;
+
+    // Profiles are separated manually, so unknown keys can still be rejected
+    let profiles = match table.remove("profiles") {
+        Some(profiles) => profiles.try_into().with_context(|| "invalid profile")?,
+        None => HashMap::new(),
+    };
+    let options = toml::Value::Table(table)
+        .try_into()
+        .with_context(|| "invalid option")?;
+
+    Ok(Config { options, profiles })
+}
+
+impl ConfigOptions {
+    /// Options in `other` take precedence.
+    fn merge(self, other: Self) -> Self {
+        Self {
+            directory: other.directory.or(self.directory),
+            start: other.start.or(self.start),
+            end: other.end.or(self.end),
+            max: other.max.or(self.max),
+            skip_failed: other.skip_failed.or(self.skip_failed),
+            on_save: other.on_save.or(self.on_save),
+            on_complete: other.on_complete.or(self.on_complete),
+            webhook: other.webhook.or(self.webhook),
+            log_file: other.log_file.or(self.log_file),
+            weekday: other.weekday.or(self.weekday),
+            format: other.format.or(self.format),
+            jobs: other.jobs.or(self.jobs),
+            attempts: other.attempts.or(self.attempts),
+            timeout: other.timeout.or(self.timeout),
+            initial_timeout: other.initial_timeout.or(self.initial_timeout),
+            proxy: other.proxy.or(self.proxy),
+            no_proxy: other.no_proxy.or(self.no_proxy),
+            proxy_strategy: other.proxy_strategy.or(self.proxy_strategy),
+            forward_proxy: other.forward_proxy.or(self.forward_proxy),
+            no_env_proxy: other.no_env_proxy.or(self.no_env_proxy),
+            always_ping: other.always_ping.or(self.always_ping),
+            cache: other.cache.or(self.cache),
+            no_cache: other.no_cache.or(self.no_cache),
+            save_cache: other.save_cache.or(self.save_cache),
+            cache_compression: other.cache_compression.or(self.cache_compression),
+            user_agent: other.user_agent.or(self.user_agent),
+            progress: other.progress.or(self.progress),
+        }
+    }
+
+    fn apply(self, args: &mut Args, matches: &ArgMatches) {
+        // Global options may be given before or after any subcommand
+        let is_given = |id: &str| {
+            let mut matches = Some(matches);
+            while let Some(current) = matches {
+                if current.try_contains_id(id).unwrap_or(false)
+                    && current.value_source(id) == Some(ValueSource::CommandLine)
+                {
+                    return true;
+                }
+                matches = current.subcommand().map(|(_, matches)| matches);
+            }
+            false
+        };
+
+        macro_rules! apply {
+            ( $( $key:ident => $field:ident ),* $(,)? ) => {
+                $(
+                    if let Some(value) = self.$key
+                        && !is_given(stringify!($field))
+                    {
+                        args.$field = value;
+                    }
+                )*
+            };
+        }
+        macro_rules! apply_optional {
+            ( $( $key:ident => $field:ident ),* $(,)? ) => {
+                $(
+                    if self.$key.is_some() && !is_given(stringify!($field)) {
+                        args.$field = self.$key;
+                    }
+                )*
+            };
+        }
+
+        apply!(
+            weekday => weekdays,
+            skip_failed => skip_failed,
+            on_save => on_save,
+            on_complete => on_complete,
+            webhook => webhooks,
+            format => image_format,
+            jobs => job_count,
+            attempts => max_attempts,
+            timeout => timeout_primary,
+            initial_timeout => timeout_initial,
+            proxy_strategy => proxy_strategy,
+            no_env_proxy => no_env_proxy,
+            always_ping => always_ping,
+            cache_compression => cache_compression,
+            user_agent => user_agent,
+            progress => progress,
+        );
+        apply_optional!(
+            directory => directory,
+            start => start_date,
+            end => end_date,
+            max => max_images,
+            forward_proxy => forward_proxy,
+            save_cache => save_cache,
+            log_file => log_file,
+        );
+
+        // Mutually exclusive options: neither is applied if either is given
+        if !is_given("proxy") && !is_given("no_proxy") {
+            apply!(proxy => proxy, no_proxy => no_proxy);
+        }
+        if !is_given("cache") && !is_given("no_cache") {
+            apply!(cache => cache, no_cache => no_cache);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory as _, FromArgMatches as _};
+
+    use super::*;
+
+    /// Empty directory, unique to the test.
+    fn temp_directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "everygarf-test-config-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join(crate::io::DATA_DIRECTORY_NAME)).unwrap();
+        path
+    }
+
+    /// Parse command line with the given user and target configs.
+    fn parse_with_configs(
+        name: &str,
+        user_config: &str,
+        target_config: &str,
+        cli: &[&str],
+    ) -> Result<Args> {
+        let directory = temp_directory(name);
+        let user_config_path = directory.join("user.toml");
+        fs::write(&user_config_path, user_config).unwrap();
+        fs::write(get_target_config_path(&directory), target_config).unwrap();
+
+        let mut argv = vec![
+            "everygarf".to_string(),
+            directory.display().to_string(),
+            "--config".to_string(),
+            user_config_path.display().to_string(),
+        ];
+        argv.extend(cli.iter().map(ToString::to_string));
+        let matches = Args::command().try_get_matches_from(argv).unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        let result = apply_config_files(&mut args, &matches);
+        fs::remove_dir_all(&directory).unwrap();
+        result.map(|()| args)
+    }
+
+    #[test]
+    fn merge_prefers_other() {
+        let base = ConfigOptions {
+            jobs: NonZero::new(2),
+            attempts: NonZero::new(3),
+            ..Default::default()
+        };
+        let other = ConfigOptions {
+            jobs: NonZero::new(8),
+            ..Default::default()
+        };
+        let merged = base.merge(other);
+        assert_eq!(merged.jobs, NonZero::new(8));
+        assert_eq!(merged.attempts, NonZero::new(3));
+    }
+
+    #[test]
+    fn configs_and_profiles_in_order() {
+        let user = r#"
+            jobs = 2
+            attempts = 2
+            timeout = 2
+            [profiles.fast]
+            jobs = 8
+            attempts = 8
+        "#;
+        let target = r#"
+            attempts = 3
+            [profiles.fast]
+            timeout = 9
+        "#;
+        let args = parse_with_configs("order", user, target, &["--profile", "fast"]).unwrap();
+        assert_eq!(args.job_count.get(), 8);
+        assert_eq!(args.max_attempts.get(), 8);
+        assert_eq!(args.timeout_primary.get(), 9);
+
+        let args = parse_with_configs("no-profile", user, target, &[]).unwrap();
+        assert_eq!(args.job_count.get(), 2);
+        assert_eq!(args.max_attempts.get(), 3);
+        assert_eq!(args.timeout_primary.get(), 2);
+    }
+
+    #[test]
+    fn user_profile_overrides_target_config() {
+        let user = r#"
+            [profiles.fast]
+            jobs = 8
+        "#;
+        let target = r#"
+            jobs = 3
+            [profiles.fast]
+            attempts = 9
+        "#;
+        let args =
+            parse_with_configs("user-profile", user, target, &["--profile", "fast"]).unwrap();
+        assert_eq!(args.job_count.get(), 8);
+        assert_eq!(args.max_attempts.get(), 9);
+
+        let args = parse_with_configs("target-base", user, target, &[]).unwrap();
+        assert_eq!(args.job_count.get(), 3);
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let user = "jobs = 2\nweekday = [\"sun\"]\n";
+        let target = "attempts = 3\n";
+        let args =
+            parse_with_configs("cli", user, target, &["--jobs", "5", "--attempts", "6"]).unwrap();
+        assert_eq!(args.job_count.get(), 5);
+        assert_eq!(args.max_attempts.get(), 6);
+        assert_eq!(args.weekdays, [Weekday::Sun]);
+
+        let args = parse_with_configs("cli-weekday", user, target, &["-w", "mon"]).unwrap();
+        assert_eq!(args.weekdays, [Weekday::Mon]);
+    }
+
+    #[test]
+    fn weekday_key_matches_option() {
+        let args = parse_with_configs("weekday", "weekday = [\"sat\", \"sun\"]", "", &[]).unwrap();
+        assert_eq!(args.weekdays, [Weekday::Sat, Weekday::Sun]);
+        let args = parse_with_configs("weekdays", "weekdays = [\"sun\"]", "", &[]).unwrap();
+        assert_eq!(args.weekdays, [Weekday::Sun]);
+    }
+
+    #[test]
+    fn missing_profile_is_error() {
+        let result = parse_with_configs("missing-profile", "", "", &["--profile", "none"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unknown_key_is_error() {
+        let result = parse_with_configs("unknown-key", "job = 2", "", &[]);
+        assert!(result.is_err());
+    }
+}
@@ -1,14 +1,23 @@
 use std::num::NonZero;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::Result;
+use chrono::NaiveDate;
 use everygarf::DateUrl;
 use futures::StreamExt as _;
-use reqwest::{Client, Url};
+use reqwest::Client;
 use tokio::sync::mpsc;
+use tracing::{Instrument as _, error, info, info_span};
 
-use crate::download::{DownloadOptions, download_image};
-use crate::state::{State, Status, Update, UpdateSuccess, UpdateWarning};
+use crate::cache::CacheData;
+use crate::download::{DownloadOptions, download_image, is_not_published};
+use crate::hooks::Hooks;
+use crate::journal::{Event, Journal};
+use crate::progress::Renderer;
+use crate::proxy::ProxyPool;
+use crate::shutdown::Shutdown;
+use crate::state::{DateContext, Goal, Stage, State, Status, Update, UpdateSuccess, UpdateWarning};
 
 pub struct Downloader {
     pub tx: Sender,
@@ -18,7 +27,11 @@ pub struct Downloader {
     pub job_count: NonZero<usize>,
     pub max_attempts: NonZero<usize>,
     pub image_format: everygarf::ImageFormat,
-    pub proxy: Option<Url>,
+    pub proxies: ProxyPool,
+    pub resolve_only: bool,
+    pub journal: Option<Arc<Journal>>,
+    pub hooks: Option<Arc<Hooks>>,
+    pub shutdown: Shutdown,
 }
 
 #[derive(Clone)]
@@ -31,6 +44,10 @@ impl Sender {
         Self { tx }
     }
 
+    pub async fn send_stage(&self, date: NaiveDate, stage: Stage) {
+        self.send(Ok(Update::EnterStage { date, stage })).await;
+    }
+
     pub async fn send_success(&self, success: UpdateSuccess) {
         self.send(Ok(Update::Success(success))).await;
     }
@@ -52,161 +69,162 @@ impl Sender {
 }
 
 impl Downloader {
-    pub async fn download_pending_images(self) {
+    /// Returns image urls of all successful downloads (or resolutions, if `resolve_only`).
+    pub async fn download_pending_images(self) -> CacheData {
         let futures = self.pending_dates.into_iter().map(|date_url| {
             let tx = self.tx.clone();
+            let span = info_span!("date", date = %date_url.date);
             let options = DownloadOptions {
                 date_url,
                 client: self.client.clone(),
                 directory: &self.directory,
                 max_attempts: self.max_attempts,
                 image_format: self.image_format,
-                proxy: self.proxy.as_ref(),
+                proxies: &self.proxies,
+                resolve_only: self.resolve_only,
+                journal: self.journal.as_deref(),
+                hooks: self.hooks.as_deref(),
             };
 
             async move {
-                if let Err(error) = download_image(&tx, options).await {
-                    tx.send_error(error).await;
-                };
-                Ok(())
+                let date = options.date_url.date;
+                let journal = options.journal;
+                let result = async {
+                    if let Some(journal) = journal {
+                        journal.record(Event::Started(date))?;
+                    }
+                    let image_url = download_image(&tx, options).await?;
+                    if let Some(journal) = journal {
+                        journal.record(Event::Done(date))?;
+                    }
+                    Ok(image_url)
+                }
+                .await;
+
+                match result {
+                    Ok(image_url) => {
+                        info!("completed");
+                        Some((date, image_url))
+                    }
+                    // Left pending in journal, to be tried by the next run
+                    Err(error) if is_not_published(&error) => {
+                        info!("not published");
+                        tx.send_warning(UpdateWarning::NotPublished { date }).await;
+                        None
+                    }
+                    Err(error) => {
+                        error!("failed: {:#}", error);
+                        if let Some(journal) = journal {
+                            // Already failing, so another error would not be useful
+                            let _ = journal.record(Event::Failed(date));
+                        }
+                        tx.send_warning(UpdateWarning::Failed { date }).await;
+                        tx.send_error(error.context(DateContext(date))).await;
+                        None
+                    }
+                }
             }
+            .instrument(span)
         });
 
-        let results: Vec<Result<()>> = futures::stream::iter(futures)
+        // In-flight dates are allowed to finish
+        futures::stream::iter(futures)
+            .take_until(self.shutdown.requested())
             .buffer_unordered(self.job_count.into())
+            .filter_map(|result| async move { result })
             .collect()
-            .await;
-
-        for result in results {
-            // TODO(feat): Send errors to main thread
-            result.unwrap()
-        }
+            .await
     }
 }
 
-pub async fn draw_progress_loop(
-    rx: &mut mpsc::Receiver<Result<Update>>,
+/// Output of worker, with final progress of run.
+pub struct Outcome<T> {
+    /// First error sent by the worker, if any.
+    pub result: Result<T>,
+    pub state: State,
+}
+
+/// Run worker task while drawing its progress.
+///
+/// Worker should stop starting new dates once `shutdown` is requested.
+pub async fn run_with_progress<W, F, T>(
+    channel_size: NonZero<usize>,
     pending_count: usize,
-) -> Result<()> {
-    let mut state = State::new(pending_count);
+    goal: Goal,
+    renderer: Renderer,
+    shutdown: &Shutdown,
+    worker: W,
+) -> Outcome<T>
+where
+    W: FnOnce(Sender) -> F,
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel(channel_size.into());
+    let worker_handle = tokio::spawn(worker(Sender::new(tx)));
+
+    let mut state = State::new(pending_count, goal);
+    if let Err(error) = draw_progress_loop(&mut rx, &mut state, renderer, shutdown).await {
+        worker_handle.abort();
+        // Wait for any additional messages, to prevent sender panicking
+        while rx.recv().await.is_some() {}
+        return Outcome {
+            result: Err(error),
+            state,
+        };
+    }
 
-    draw_progress(&mut state, false);
+    // TODO(feat): Handle better
+    match worker_handle.await {
+        Ok(output) => Outcome {
+            result: Ok(output),
+            state,
+        },
+        Err(error) => panic!("{}", error),
+    }
+}
 
-    while let Some(msg) = rx.recv().await {
+async fn draw_progress_loop(
+    rx: &mut mpsc::Receiver<Result<Update>>,
+    state: &mut State,
+    mut renderer: Renderer,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    renderer.start(state);
+
+    loop {
+        let msg = tokio::select! {
+            msg = rx.recv() => msg,
+            () = shutdown.requested(), if state.status() != Status::Stopping => {
+                state.set_stopping();
+                renderer.stopping(state);
+                continue;
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
         match msg {
             Ok(update) => {
+                #[cfg(feature = "metrics")]
+                crate::metrics::record(update);
                 state.update(update);
-                draw_progress(&mut state, false);
+                renderer.update(state, update);
             }
             Err(error) => {
-                state.set_failed();
-                draw_progress(&mut state, true);
+                state.set_failed(&error);
+                renderer.fail(state, &error);
                 return Err(error);
             }
         }
     }
 
-    state.update(Update::Success(UpdateSuccess::Complete));
-    draw_progress(&mut state, true);
-
-    Ok(())
-}
-
-fn draw_progress(state: &mut State, concise: bool) {
-    let line_count = 4;
-    let bar_width = 40;
-
-    let current = state.completed_units();
-    let total = state.total_units();
-
-    let (percent, bar_progress) = if total == 0 {
-        (100.0, bar_width)
+    if shutdown.is_requested() {
+        state.set_interrupted();
     } else {
-        (
-            current as f32 * 100.0 / total as f32,
-            current * bar_width / total,
-        )
-    };
-
-    if !state.record_draw() {
-        for _ in 0..line_count {
-            print!("\r"); // Move cursor to beginning of line
-            print!("\x1b[1A"); // Move cursor up
-            print!("\x1b[2K"); // Clear entire line
-        }
-    }
-
-    // Always draw progress bar no matter the context
-    print!("{:6.2}%", percent);
-    print!(" [");
-    for i in 0..bar_width {
-        if i < bar_progress {
-            print!("#");
-        } else {
-            print!(".");
-        }
-    }
-    print!("]");
-    println!();
-
-    if concise {
-        return;
-    }
-
-    print!(" status: ");
-    match state.status() {
-        Status::PingProxy => println!("pinging proxy server..."),
-        Status::FetchCache => println!("downloading url cache..."),
-        Status::Working => println!("in progress..."),
-        Status::Complete => println!("all done."),
-        Status::Failed => println!("failed!"),
+        state.update(Update::Success(UpdateSuccess::Complete));
     }
+    renderer.finish(state);
 
-    print!(" latest: ");
-    match state.latest_success() {
-        None => println!("started."),
-
-        Some(UpdateSuccess::ProxyPing) => {
-            println!("proxy server working.");
-        }
-        Some(UpdateSuccess::FetchCache) => {
-            println!("downloaded url cache.");
-        }
-
-        Some(UpdateSuccess::FetchUrl { date }) => {
-            println!("{} | fetched image url.", date);
-        }
-        Some(UpdateSuccess::FetchImage { date }) => {
-            println!("{} | downloaded image.", date)
-        }
-        Some(UpdateSuccess::SaveImage { date }) => {
-            println!("{} | saved image.", date);
-        }
-
-        Some(UpdateSuccess::Complete) => {
-            unreachable!("if recieved `Complete` message, display should from now on be `concise`");
-        }
-    }
-
-    if let Some(warning) = state.latest_warning() {
-        print!("warning: ");
-        match warning {
-            UpdateWarning::FetchUrl { attempt, date } => {
-                println!(
-                    "{} | failed to fetch image url (attempt {}).",
-                    date,
-                    attempt + 1,
-                );
-            }
-            UpdateWarning::FetchImage { attempt, date } => {
-                println!(
-                    "{} | failed to download image (attempt {}).",
-                    date,
-                    attempt + 1,
-                );
-            }
-        }
-    } else {
-        println!();
-    }
+    Ok(())
 }
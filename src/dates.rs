@@ -1,26 +1,28 @@
 use std::ops::RangeInclusive;
 
-use chrono::{Duration, NaiveDate, NaiveTime, Utc};
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
 
 pub const FIRST_DATE: NaiveDate = NaiveDate::from_ymd_opt(1978, 6, 19).unwrap();
 
-pub fn latest() -> NaiveDate {
-    // TODO(refactor)
-    use chrono::Duration;
+// Get naive time (UTC) for when comic is published to gocomics.com
+// Estimated time is:
+//      0000-0300 EST
+//      0400-0700 UTC
+//      1400-1700 AEST
+// And a margin of error is added just in case
+const TIME_OF_PUBLISH: NaiveTime = NaiveTime::from_hms_opt(7, 0, 0).unwrap();
 
+pub fn latest() -> NaiveDate {
     let now = Utc::now();
 
-    // Get naive time (UTC) for when comic is published to gocomics.com
-    // Estimated time is:
-    //      0000-0300 EST
-    //      0400-0700 UTC
-    //      1400-1700 AEST
-    // And a margin of error is added just in case
-    let time_of_publish = const { NaiveTime::from_hms_opt(7, 0, 0).unwrap() };
-
     // Today if currently AFTER time of publish for todays comic
     // Yesterday if currently BEFORE time of publish for todays comic
-    now.date_naive() - Duration::days(if now.time() > time_of_publish { 0 } else { 1 })
+    now.date_naive() - Duration::days(if now.time() > TIME_OF_PUBLISH { 0 } else { 1 })
+}
+
+/// Estimated time when comic of this date is published.
+pub fn publish_time(date: NaiveDate) -> DateTime<Utc> {
+    date.and_time(TIME_OF_PUBLISH).and_utc()
 }
 
 pub fn date_iter(range: RangeInclusive<NaiveDate>) -> impl Iterator<Item = NaiveDate> {
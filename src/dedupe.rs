@@ -0,0 +1,422 @@
+//! Find strips which are saved for more than one date, such as reruns or images which the source
+//! served for the wrong date.
+//!
+//! Identical files are found by SHA-256 hash. Near-identical images, such as the same strip
+//! encoded differently, are found by a difference hash of the decoded image, which is compared
+//! by the number of bits which differ. Near matches may be different strips with a similar
+//! layout, so they are only reported separately, and not queued unless asked.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::{Context as _, Result, bail};
+use chrono::{Datelike as _, NaiveDate, Weekday};
+use image::GenericImageView as _;
+use image::imageops::FilterType;
+use sha2::{Digest as _, Sha256};
+
+use crate::get_filename_date;
+use crate::io::{DATA_DIRECTORY_NAME, get_target_directory};
+
+/// Directory inside data directory, where duplicates are moved when queued.
+const DUPLICATES_DIRECTORY_NAME: &str = "duplicates";
+/// Size of grid which the image is reduced to, for perceptual hash. Strips are much wider than
+/// they are tall, so more columns are compared than rows.
+const HASH_COLUMNS: u32 = 16;
+const HASH_ROWS: u32 = 8;
+/// Sunday strips are taller than daily strips, relative to their width.
+const SUNDAY_MAX_ASPECT_RATIO: f64 = 2.5;
+
+struct Image {
+    date: NaiveDate,
+    path: PathBuf,
+    digest: [u8; 32],
+    hash: u128,
+    width: u32,
+    height: u32,
+}
+
+/// Images of the same strip as the one for the date it most likely belongs to.
+struct Group<'a> {
+    chosen: &'a Image,
+    identical: Vec<&'a Image>,
+    /// With distance from chosen image.
+    similar: Vec<(&'a Image, u32)>,
+}
+
+pub fn run(
+    directory: Option<PathBuf>,
+    max_distance: u32,
+    queue: bool,
+    queue_similar: bool,
+) -> Result<()> {
+    let directory = match directory {
+        Some(directory) => directory,
+        None => get_target_directory()
+            .with_context(|| "failed to find appropriate target directory path")?,
+    };
+    if !directory.is_dir() {
+        bail!("target directory does not exist: {}", directory.display());
+    }
+
+    let paths = find_images(&directory).with_context(|| "failed to read target directory")?;
+    let images = hash_images(paths);
+    let groups = find_groups(&images, max_distance);
+
+    if groups.is_empty() {
+        println!("no duplicates in {} images", images.len());
+        return Ok(());
+    }
+    for group in &groups {
+        print_group(group);
+    }
+    let identical: Vec<_> = groups
+        .iter()
+        .flat_map(|group| &group.identical)
+        .copied()
+        .collect();
+    let similar: Vec<_> = groups
+        .iter()
+        .flat_map(|group| &group.similar)
+        .map(|(image, _)| *image)
+        .collect();
+    println!(
+        "{} identical and {} similar dates in {} groups, out of {} images",
+        identical.len(),
+        similar.len(),
+        groups.len(),
+        images.len(),
+    );
+
+    if !queue {
+        println!("run again with `--queue` to download identical dates again");
+        return Ok(());
+    }
+    let mut duplicates = identical;
+    if queue_similar {
+        duplicates.extend(similar);
+    } else if !similar.is_empty() {
+        println!("not moving similar images; add `--queue-similar` to move them too");
+    }
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+    let target = directory
+        .join(DATA_DIRECTORY_NAME)
+        .join(DUPLICATES_DIRECTORY_NAME);
+    fs::create_dir_all(&target).with_context(|| "failed to create duplicates directory")?;
+    for image in duplicates {
+        let file_name = image.path.file_name().expect("image path has file name");
+        fs::rename(&image.path, target.join(file_name))
+            .with_context(|| format!("failed to move image for {}", image.date))?;
+    }
+    // Cached url may be the one which served the wrong image
+    println!(
+        "moved duplicates to {}; run `everygarf --no-cache` to download them again",
+        target.display(),
+    );
+    Ok(())
+}
+
+fn find_images(directory: &Path) -> Result<Vec<(NaiveDate, PathBuf)>> {
+    let mut images = Vec::new();
+    for child in fs::read_dir(directory)? {
+        let path = child?.path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(date) = get_filename_date(&path) {
+            images.push((date, path));
+        }
+    }
+    images.sort();
+    Ok(images)
+}
+
+/// Split between threads, as decoding every image is slow. Images which cannot be read are
+/// skipped with a warning.
+fn hash_images(paths: Vec<(NaiveDate, PathBuf)>) -> Vec<Image> {
+    let thread_count = thread::available_parallelism().map_or(1, usize::from);
+    let chunk_size = paths.len().div_ceil(thread_count).max(1);
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let mut images = Vec::new();
+                    for (date, path) in chunk {
+                        match hash_image(*date, path) {
+                            Ok(image) => images.push(image),
+                            Err(error) => {
+                                eprintln!("warning: skipping image for {}: {:#}", date, error);
+                            }
+                        }
+                    }
+                    images
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("hashing thread panicked"))
+            .collect()
+    })
+}
+
+fn hash_image(date: NaiveDate, path: &Path) -> Result<Image> {
+    let bytes = fs::read(path).with_context(|| "reading image file")?;
+    let digest = Sha256::digest(&bytes).into();
+    let decoded = image::load_from_memory(&bytes).with_context(|| "decoding image")?;
+    let (width, height) = decoded.dimensions();
+    Ok(Image {
+        date,
+        path: path.to_path_buf(),
+        digest,
+        hash: difference_hash(&decoded),
+        width,
+        height,
+    })
+}
+
+/// One bit per pair of horizontally adjacent cells, set if brightness increases.
+fn difference_hash(image: &image::DynamicImage) -> u128 {
+    let grid = image
+        .resize_exact(HASH_COLUMNS + 1, HASH_ROWS, FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0;
+    for y in 0..HASH_ROWS {
+        for x in 0..HASH_COLUMNS {
+            let bit = grid.get_pixel(x, y).0[0] < grid.get_pixel(x + 1, y).0[0];
+            hash = hash << 1 | u128::from(bit);
+        }
+    }
+    hash
+}
+
+/// Each image joins the first group whose chosen image it is identical or near-identical to,
+/// otherwise it is chosen for a new group. Images are visited in order of preference, so the
+/// chosen image is the one most likely correct. Images are not joined through other members, so
+/// a chain of small differences cannot group unrelated strips.
+fn find_groups(images: &[Image], max_distance: u32) -> Vec<Group<'_>> {
+    let mut sorted: Vec<&Image> = images.iter().collect();
+    sorted.sort_by_key(|image| (!matches_weekday(image), image.date));
+
+    let mut groups: Vec<Group> = Vec::new();
+    for image in sorted {
+        let group = groups.iter_mut().find(|group| {
+            group.chosen.digest == image.digest || distance(group.chosen, image) <= max_distance
+        });
+        match group {
+            Some(group) if group.chosen.digest == image.digest => group.identical.push(image),
+            Some(group) => {
+                let distance = distance(group.chosen, image);
+                group.similar.push((image, distance));
+            }
+            None => groups.push(Group {
+                chosen: image,
+                identical: Vec::new(),
+                similar: Vec::new(),
+            }),
+        }
+    }
+
+    groups.retain(|group| !group.identical.is_empty() || !group.similar.is_empty());
+    groups.sort_by_key(|group| group.chosen.date);
+    groups
+}
+
+/// A rerun or glitch serves an existing strip for a later date, so the earliest date is most
+/// likely correct. A Sunday strip saved for a weekday (or the reverse) is never correct.
+fn matches_weekday(image: &Image) -> bool {
+    let is_sunday_shape =
+        f64::from(image.width) / f64::from(image.height.max(1)) < SUNDAY_MAX_ASPECT_RATIO;
+    is_sunday_shape == (image.date.weekday() == Weekday::Sun)
+}
+
+fn distance(a: &Image, b: &Image) -> u32 {
+    (a.hash ^ b.hash).count_ones()
+}
+
+fn print_group(group: &Group) {
+    println!("{}  likely correct", display_file(group.chosen));
+    for image in &group.identical {
+        println!("{}  identical", display_file(image));
+    }
+    for (image, distance) in &group.similar {
+        println!("{}  similar (distance {})", display_file(image), distance);
+    }
+    println!();
+}
+
+fn display_file(image: &Image) -> String {
+    let name = image.path.file_name().unwrap_or_default().to_string_lossy();
+    format!("{} {:<14}", image.date.format("%a"), name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, GrayImage, ImageFormat, Luma};
+
+    use super::*;
+    use crate::args::defaults::DEDUPE_MAX_DISTANCE;
+
+    const DAILY_SIZE: (u32, u32) = (600, 200);
+    const SUNDAY_SIZE: (u32, u32) = (600, 400);
+
+    fn date(day: u32) -> NaiveDate {
+        // 2000-01-03 is Monday
+        NaiveDate::from_ymd_opt(2000, 1, day).unwrap()
+    }
+
+    fn image(day: u32, digest: u8, hash: u128, (width, height): (u32, u32)) -> Image {
+        Image {
+            date: date(day),
+            path: PathBuf::from(format!("2000-01-{:02}.png", day)),
+            digest: [digest; 32],
+            hash,
+            width,
+            height,
+        }
+    }
+
+    /// Three bordered panels, each with blocks of shading placed by the seed. Strips with
+    /// different seeds share a layout, like different strips from the same period.
+    fn strip(seed: u64) -> DynamicImage {
+        let (width, height) = DAILY_SIZE;
+        let mut state = seed;
+        let mut next = move |max: u32| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as u32 % max
+        };
+        let mut pixels = GrayImage::from_pixel(width, height, Luma([255]));
+        let panel_width = width / 3;
+        for panel in 0..3 {
+            let left = panel * panel_width;
+            for _ in 0..8 {
+                let x = left + 10 + next(panel_width - 60);
+                let y = 10 + next(height - 60);
+                let shade = next(200) as u8;
+                for dy in 0..40 {
+                    for dx in 0..40 {
+                        pixels.put_pixel(x + dx, y + dy, Luma([shade]));
+                    }
+                }
+            }
+            for y in 0..height {
+                for x in [left, left + panel_width - 1] {
+                    pixels.put_pixel(x, y, Luma([0]));
+                }
+            }
+        }
+        DynamicImage::ImageLuma8(pixels)
+    }
+
+    fn reencode(image: &DynamicImage, format: ImageFormat) -> DynamicImage {
+        let mut bytes = Vec::new();
+        image
+            .to_rgb8()
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        image::load_from_memory(&bytes).unwrap()
+    }
+
+    fn hash_distance(a: &DynamicImage, b: &DynamicImage) -> u32 {
+        (difference_hash(a) ^ difference_hash(b)).count_ones()
+    }
+
+    fn dates(images: &[&Image]) -> Vec<NaiveDate> {
+        images.iter().map(|image| image.date).collect()
+    }
+
+    #[test]
+    fn reencoded_strip_is_near_identical() {
+        let original = strip(1);
+        let jpeg = reencode(&original, ImageFormat::Jpeg);
+        assert!(hash_distance(&original, &jpeg) <= DEDUPE_MAX_DISTANCE);
+        let resized = original.resize_exact(900, 300, FilterType::Triangle);
+        assert!(hash_distance(&original, &resized) <= DEDUPE_MAX_DISTANCE);
+    }
+
+    #[test]
+    fn similar_strips_are_not_grouped() {
+        let first = strip(1);
+        let second = strip(2);
+        assert!(hash_distance(&first, &second) > DEDUPE_MAX_DISTANCE * 4);
+
+        let (width, height) = DAILY_SIZE;
+        let images = [
+            Image {
+                hash: difference_hash(&first),
+                ..image(3, 1, 0, (width, height))
+            },
+            Image {
+                hash: difference_hash(&second),
+                ..image(4, 2, 0, (width, height))
+            },
+        ];
+        assert!(find_groups(&images, DEDUPE_MAX_DISTANCE).is_empty());
+    }
+
+    #[test]
+    fn identical_and_similar_are_separate() {
+        let images = [
+            image(3, 1, 0b0000, DAILY_SIZE),
+            image(4, 1, 0b0000, DAILY_SIZE),
+            image(5, 2, 0b0011, DAILY_SIZE),
+            image(6, 3, 0b1111_1111, DAILY_SIZE),
+        ];
+        let groups = find_groups(&images, 2);
+        assert_eq!(groups.len(), 1);
+        let group = &groups[0];
+        assert_eq!(group.chosen.date, date(3));
+        assert_eq!(dates(&group.identical), [date(4)]);
+        assert_eq!(group.similar.len(), 1);
+        assert_eq!(group.similar[0].0.date, date(5));
+        assert_eq!(group.similar[0].1, 2);
+    }
+
+    #[test]
+    fn groups_are_not_transitive() {
+        // Each image is within distance of the next, but the last is far from the first
+        let images = [
+            image(3, 1, 0b0000, DAILY_SIZE),
+            image(4, 2, 0b0011, DAILY_SIZE),
+            image(5, 3, 0b1111, DAILY_SIZE),
+        ];
+        let groups = find_groups(&images, 2);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].chosen.date, date(3));
+        assert_eq!(groups[0].similar[0].0.date, date(4));
+        assert!(groups[0].identical.is_empty());
+        assert_eq!(groups[0].similar.len(), 1);
+    }
+
+    #[test]
+    fn chosen_image_matches_weekday() {
+        // Sunday strip, saved first for Saturday
+        let images = [
+            image(8, 1, 0, SUNDAY_SIZE),
+            image(9, 1, 0, SUNDAY_SIZE),
+            image(10, 2, 1 << 40, DAILY_SIZE),
+        ];
+        let groups = find_groups(&images, 0);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].chosen.date, date(9));
+        assert_eq!(dates(&groups[0].identical), [date(8)]);
+    }
+
+    #[test]
+    fn weekday_shape() {
+        assert!(matches_weekday(&image(3, 0, 0, DAILY_SIZE)));
+        assert!(!matches_weekday(&image(3, 0, 0, SUNDAY_SIZE)));
+        assert!(matches_weekday(&image(9, 0, 0, SUNDAY_SIZE)));
+        assert!(!matches_weekday(&image(9, 0, 0, DAILY_SIZE)));
+        assert!(!matches_weekday(&image(3, 0, 0, (0, 0))));
+    }
+}
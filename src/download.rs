@@ -1,22 +1,30 @@
-use std::fmt::Write as _;
+use std::fmt::{self, Write as _};
 use std::fs;
 use std::num::NonZero;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context as _, Result, anyhow};
+use anyhow::{Context as _, Result};
 use bytes::Bytes;
-use chrono::NaiveDate;
+use chrono::{NaiveDate, Utc};
 use everygarf::{DateUrl, ImageFormat, UrlPath};
-use reqwest::{Client, Url};
+use reqwest::{Client, StatusCode, Url, header};
+use tracing::{Instrument as _, debug, debug_span, warn};
 
+use crate::cache::{
+    Cache, CacheData, StoredCache, load_stored_cache, parse_cached_urls, store_cache,
+};
 use crate::controller::Sender;
-use crate::state::{UpdateSuccess, UpdateWarning};
+use crate::dates;
+use crate::hooks::{HookEvent, Hooks};
+use crate::io::PARTIAL_EXTENSION;
+use crate::journal::{self, Journal};
+use crate::proxy::ProxyPool;
+use crate::state::{Stage, UpdateSuccess, UpdateWarning};
 
 // TODO(refactor): Move these
-const IMAGE_URL_PREFIX: &str = "https://featureassets.gocomics.com/assets/";
+pub const IMAGE_URL_PREFIX: &str = "https://featureassets.gocomics.com/assets/";
 const IMAGE_URL_LENGTH: usize = 74;
-
-pub type CacheData = std::collections::HashMap<NaiveDate, Url>;
+const PAGE_BASE_URL: &str = "https://www.gocomics.com/garfield";
 
 pub struct DownloadOptions<'a> {
     pub date_url: DateUrl,
@@ -24,19 +32,99 @@ pub struct DownloadOptions<'a> {
     pub directory: &'a Path,
     pub max_attempts: NonZero<usize>,
     pub image_format: ImageFormat,
-    pub proxy: Option<&'a Url>,
+    pub proxies: &'a ProxyPool,
+    /// Stop after image url is resolved.
+    pub resolve_only: bool,
+    pub journal: Option<&'a Journal>,
+    pub hooks: Option<&'a Hooks>,
+}
+
+/// Comic for a date does not exist yet, as opposed to failing to fetch it.
+#[derive(Debug)]
+pub struct NotPublished(pub NaiveDate);
+
+impl fmt::Display for NotPublished {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "comic for {} is not published yet", self.0)
+    }
+}
+
+impl std::error::Error for NotPublished {}
+
+pub fn is_not_published(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<NotPublished>())
 }
 
-pub async fn check_proxy(tx: &Sender, client: &Client, proxy: Option<&Url>) -> Result<(), ()> {
-    let Some(proxy) = proxy else {
+/// Page for an older date does not exist at the source.
+#[derive(Debug)]
+pub struct MissingFromSource(&'static str);
+
+impl fmt::Display for MissingFromSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "comic is missing from source: {}", self.0)
+    }
+}
+
+impl std::error::Error for MissingFromSource {}
+
+pub fn is_missing_from_source(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<MissingFromSource>())
+}
+
+/// Latest date with a published comic, found by checking today and yesterday.
+/// Falls back to an estimate by time of day, if the source cannot be reached.
+pub async fn find_latest_date(client: &Client, proxies: &ProxyPool) -> NaiveDate {
+    let today = Utc::now().date_naive();
+    let mut date = today;
+    for _ in 0..2 {
+        match probe_date(client, proxies, date).await {
+            Ok(()) => return date,
+            Err(error) if is_not_published(&error) => date = date.pred_opt().unwrap(),
+            Err(error) => {
+                warn!("failed to find latest date, using estimate: {:#}", error);
+                return dates::latest();
+            }
+        }
+    }
+    // Comics are published every day
+    date
+}
+
+async fn probe_date(client: &Client, proxies: &ProxyPool, date: NaiveDate) -> Result<()> {
+    let proxy = proxies.pick().map(|(_, url)| url);
+    let page_url = get_page_url(proxy, PAGE_BASE_URL, date);
+    let body = fetch_page(client, &page_url, date).await?;
+    find_image_url(&body)?;
+    Ok(())
+}
+
+/// Disable any proxies which cannot be reached.
+/// If none work, fall back to direct requests, if those work.
+pub async fn check_proxies(tx: &Sender, client: &Client, proxies: &ProxyPool) -> Result<(), ()> {
+    if proxies.is_empty() {
         return Ok(());
-    };
+    }
 
-    if let Err(_error) = fetch_response(client, proxy.clone()).await {
-        tx.send_error(anyhow!("failed to access proxy server"))
-            .await;
-        return Err(());
-    };
+    let checks = proxies.urls().map(|(index, url)| async move {
+        let result = fetch_response(client, url.clone()).await;
+        (index, result)
+    });
+    for (index, result) in futures::future::join_all(checks).await {
+        if let Err(error) = result {
+            warn!(index, "disabling unreachable proxy: {:#}", error);
+            proxies.disable(index);
+            tx.send_warning(UpdateWarning::DisableProxy { index }).await;
+        }
+    }
+
+    if proxies.enabled_count() == 0 {
+        let direct_url = Url::parse(PAGE_BASE_URL).expect("Base page url should be valid");
+        if let Err(error) = fetch_response(client, direct_url).await {
+            tx.send_error(error.context("failed to access any proxy server, or source directly"))
+                .await;
+            return Err(());
+        }
+    }
 
     tx.send_success(UpdateSuccess::ProxyPing).await;
     Ok(())
@@ -47,83 +135,206 @@ pub async fn fetch_cached_urls(
     client: &Client,
     cache_url: UrlPath,
 ) -> Result<CacheData> {
-    // TODO(opt): Parse from stream
-    let text = match cache_url {
-        UrlPath::Remote(url) => fetch_text(client, url)
-            .await
-            .with_context(|| "fetching file")?,
-        UrlPath::Local(path) => fs::read_to_string(path).with_context(|| "reading file")?,
-    };
-    let cache_data = parse_cached_urls(&text).with_context(|| "malformed cache file");
-    tx.send_success(UpdateSuccess::FetchCache).await;
-    cache_data
+    let cache = load_cache(client, cache_url).await?;
+    let generated = cache.header.map(|header| header.generated);
+    tx.send_success(UpdateSuccess::FetchCache { generated })
+        .await;
+    Ok(cache.entries)
 }
 
-fn parse_cached_urls(text: &str) -> Result<CacheData> {
-    let mut entries = CacheData::new();
-    for line in text.lines() {
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
+pub async fn load_cache(client: &Client, cache_url: UrlPath) -> Result<Cache> {
+    // TODO(opt): Parse from stream
+    match cache_url {
+        UrlPath::Remote(url) => fetch_remote_cache(client, url).await,
+        UrlPath::Local(path) => {
+            let bytes = fs::read(path).with_context(|| "reading file")?;
+            parse_cached_urls(&bytes).with_context(|| "malformed cache file")
         }
-        // TODO(feat): Handle errors
-        let (date_string, url_path) =
-            split_columns(line).with_context(|| "bad line or something")?;
-        // TODO(feat): Extract format string to constant in all contexts
-        let date =
-            NaiveDate::parse_from_str(date_string, "%Y-%m-%d").with_context(|| "bad datej")?;
-        let image_url = expand_image_url(url_path.trim()).with_context(|| "bad url path")?;
-        entries.insert(date, image_url);
     }
-    Ok(entries)
 }
 
-fn split_columns(line: &str) -> Option<(&str, &str)> {
-    Some(line.split_at(line.find(' ')?))
-}
+/// Revalidates the local copy of the remote cache, if one exists.
+/// Falls back to the local copy if the remote cache cannot be reached, or is malformed.
+/// Local copy is only replaced once the remote cache has been parsed.
+async fn fetch_remote_cache(client: &Client, url: Url) -> Result<Cache> {
+    let stored = load_stored_cache(&url);
+    let parse_stored = |stored: StoredCache| {
+        parse_cached_urls(&stored.bytes).with_context(|| "malformed local copy of remote cache")
+    };
+
+    let mut request = client.get(url.clone());
+    if let Some(stored) = &stored {
+        if let Some(etag) = &stored.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &stored.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = match request.send().await.and_then(|r| r.error_for_status()) {
+        Ok(response) => response,
+        Err(error) => {
+            return match stored {
+                Some(stored) => {
+                    warn!("using local copy of remote cache: {}", error);
+                    parse_stored(stored)
+                }
+                None => Err(error).with_context(|| "requesting remote cache"),
+            };
+        }
+    };
+    debug!(url = %url, status = %response.status(), "received remote cache response");
 
-fn expand_image_url(url_path: &str) -> Option<Url> {
-    // Assumes base url is well-formed. The only parsing error should be a malformed path
-    let url = IMAGE_URL_PREFIX.to_string() + url_path;
-    Url::parse(&url).ok()
+    if response.status() == StatusCode::NOT_MODIFIED
+        && let Some(stored) = stored
+    {
+        return parse_stored(stored);
+    }
+
+    let get_header = |name| {
+        let value = response.headers().get(name)?;
+        Some(value.to_str().ok()?.to_string())
+    };
+    let etag = get_header(header::ETAG);
+    let last_modified = get_header(header::LAST_MODIFIED);
+
+    let bytes = match response.bytes().await {
+        Ok(bytes) => bytes.to_vec(),
+        Err(error) => {
+            return match stored {
+                Some(stored) => parse_stored(stored),
+                None => Err(error).with_context(|| "reading remote cache"),
+            };
+        }
+    };
+
+    let cache = match parse_cached_urls(&bytes) {
+        Ok(cache) => cache,
+        Err(error) => {
+            return match stored {
+                Some(stored) => {
+                    warn!("using local copy of remote cache: {:#}", error);
+                    parse_stored(stored)
+                }
+                None => Err(error).with_context(|| "malformed cache file"),
+            };
+        }
+    };
+    let stored = StoredCache {
+        bytes,
+        etag,
+        last_modified,
+    };
+    // Failing to keep a local copy should not prevent this run
+    let _ = store_cache(&url, &stored);
+    Ok(cache)
 }
 
-pub async fn download_image<'a>(tx: &Sender, options: DownloadOptions<'a>) -> Result<()> {
+/// Returns the resolved image url, to allow caching.
+pub async fn download_image<'a>(tx: &Sender, options: DownloadOptions<'a>) -> Result<Url> {
     let date = options.date_url.date;
 
     let image_url = match options.date_url.image_url {
-        Some(image_url) => image_url,
+        Some(image_url) => {
+            // Cached urls count as resolved
+            if options.resolve_only {
+                tx.send_success(UpdateSuccess::FetchUrl { date }).await;
+            }
+            image_url
+        }
         None => {
-            let image_url = try_attempts(
-                &tx,
-                options.max_attempts.into(),
-                || fetch_image_url(date, &options.client, options.proxy),
-                |attempt, _| UpdateWarning::FetchUrl { attempt, date },
+            let image_url = resolve_image_url(
+                tx,
+                date,
+                &options.client,
+                options.max_attempts,
+                options.proxies,
             )
-            .await
-            .with_context(|| "failed to fetch image url")?;
-
-            tx.send_success(UpdateSuccess::FetchUrl { date }).await;
+            .await?;
+            if let Some(journal) = options.journal {
+                journal.record(journal::Event::Resolved(date, &image_url))?;
+            }
             image_url
         }
     };
 
+    if options.resolve_only {
+        return Ok(image_url);
+    }
+
+    tx.send_stage(date, Stage::FetchImage).await;
     let image_bytes = try_attempts(
-        &tx,
+        tx,
         options.max_attempts.into(),
         || fetch_bytes(&options.client, image_url.clone()),
         |attempt, _| UpdateWarning::FetchImage { attempt, date },
     )
+    .instrument(debug_span!("fetch_image"))
     .await
     .with_context(|| "failed to fetch image data")?;
 
-    tx.send_success(UpdateSuccess::FetchImage { date }).await;
+    tx.send_success(UpdateSuccess::FetchImage {
+        date,
+        bytes: image_bytes.len() as u64,
+    })
+    .await;
 
-    save_image(date, image_bytes, options.directory, options.image_format)
+    tx.send_stage(date, Stage::SaveImage).await;
+    let path = debug_span!("save_image")
+        .in_scope(|| save_image(date, image_bytes, options.directory, options.image_format))
         .with_context(|| "failed to save image")?;
 
     tx.send_success(UpdateSuccess::SaveImage { date }).await;
 
+    if let Some(hooks) = options.hooks {
+        let event = HookEvent::Save {
+            date,
+            path: &path,
+            url: &image_url,
+            format: options.image_format,
+        };
+        // Image is already saved, so the date is still complete
+        if let Err(error) = hooks.run(&event).await {
+            warn!("save hook failed: {:#}", error);
+            tx.send_warning(UpdateWarning::HookFailed { date }).await;
+        }
+    }
+
+    Ok(image_url)
+}
+
+pub async fn resolve_image_url(
+    tx: &Sender,
+    date: NaiveDate,
+    client: &Client,
+    max_attempts: NonZero<usize>,
+    proxies: &ProxyPool,
+) -> Result<Url> {
+    tx.send_stage(date, Stage::ResolveUrl).await;
+    let image_url = try_attempts(
+        tx,
+        max_attempts.into(),
+        || fetch_image_url(tx, date, client, proxies),
+        |attempt, _| UpdateWarning::FetchUrl { attempt, date },
+    )
+    .instrument(debug_span!("resolve_url"))
+    .await
+    .with_context(|| "failed to fetch image url")?;
+
+    tx.send_success(UpdateSuccess::FetchUrl { date }).await;
+    Ok(image_url)
+}
+
+/// Check that an image exists, without downloading it.
+pub async fn check_image_url(client: &Client, url: Url) -> Result<()> {
+    client
+        .head(url)
+        .send()
+        .await
+        .with_context(|| "sending request")?
+        .error_for_status()
+        .with_context(|| "bad response status")?;
     Ok(())
 }
 
@@ -143,24 +354,127 @@ where
     loop {
         match func().await {
             Ok(ok) => return Ok(ok),
-            Err(error) if i < attempts => tx.send_warning(warning(i, error)).await,
+            // Would not change by trying again
+            Err(error) if is_not_published(&error) || is_missing_from_source(&error) => {
+                return Err(error);
+            }
+            Err(error) if i < attempts => {
+                warn!(attempt = i + 1, "attempt failed, retrying: {:#}", error);
+                tx.send_warning(warning(i, error)).await;
+            }
             Err(error) => return Err(error),
         }
         i += 1;
     }
 }
 
-async fn fetch_image_url(date: NaiveDate, client: &Client, proxy: Option<&Url>) -> Result<Url> {
-    // TODO(refactor): Extract as constant `&str`
-    let page_url = get_page_url(proxy, "https://www.gocomics.com/garfield", date);
+async fn fetch_image_url(
+    tx: &Sender,
+    date: NaiveDate,
+    client: &Client,
+    proxies: &ProxyPool,
+) -> Result<Url> {
+    let proxy = proxies.pick();
+    let page_url = get_page_url(proxy.map(|(_, url)| url), PAGE_BASE_URL, date);
+
+    #[cfg(feature = "metrics")]
+    let started = std::time::Instant::now();
+    let body = fetch_page(client, &page_url, date).await;
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_request(Stage::ResolveUrl, started.elapsed(), &body);
+    // Only request failures are attributed to proxy, not missing pages or image urls
+    let is_success = match &body {
+        Ok(_) => true,
+        Err(error) => is_not_published(error) || is_missing_from_source(error),
+    };
+    if let Some((index, _)) = proxy
+        && proxies.report(index, is_success)
+    {
+        warn!(index, "disabling proxy after repeated failures");
+        tx.send_warning(UpdateWarning::DisableProxy { index }).await;
+    }
 
-    // TODO(feat): Add error contexts
-    let response = client.get(&page_url).send().await?.error_for_status()?;
-    let body = response.text().await?;
-    let image_url = find_image_url(&body).with_context(|| "extracting image url from page")?;
+    let image_url = find_image_url(&body?).with_context(|| "extracting image url from page")?;
     Ok(image_url)
 }
 
+async fn fetch_page(client: &Client, page_url: &str, date: NaiveDate) -> Result<String> {
+    debug!(url = page_url, "requesting page");
+    let response = client
+        .get(page_url)
+        .send()
+        .await
+        .with_context(|| "sending page request")?;
+    debug!(url = %response.url(), status = %response.status(), "received page response");
+    // Missing dates are either not found, or redirected to another date
+    if response.status() == StatusCode::NOT_FOUND {
+        return Err(missing_page_error(date, "page not found"));
+    }
+    let response = response
+        .error_for_status()
+        .with_context(|| "bad response status")?;
+    let body = response.text().await.with_context(|| "reading page body")?;
+
+    // Redirects may be followed by a url-prefix proxy, so the page itself is checked
+    match find_canonical_url(&body) {
+        Some(canonical) if !is_page_for_date(canonical, date) => {
+            debug!(canonical, "page is for another date");
+            Err(missing_page_error(date, "redirected to another date"))
+        }
+        Some(_) => Ok(body),
+        None => {
+            debug!("page has no canonical url, assuming it is for requested date");
+            Ok(body)
+        }
+    }
+}
+
+/// Only recent dates may be missing because they are not published yet. Older dates are missing
+/// from the source, which is a failure.
+fn missing_page_error(date: NaiveDate, reason: &'static str) -> anyhow::Error {
+    if date >= dates::latest() - chrono::Duration::days(1) {
+        NotPublished(date).into()
+    } else {
+        MissingFromSource(reason).into()
+    }
+}
+
+/// Url which the page declares as its own, from `<link rel="canonical">` or `og:url`.
+fn find_canonical_url(body: &str) -> Option<&str> {
+    body.split('<').skip(1).find_map(|tag| {
+        let tag = &tag[..tag.find('>')?];
+        if tag.starts_with("link") && tag.contains(r#"rel="canonical""#) {
+            find_attribute(tag, "href")
+        } else if tag.starts_with("meta") && tag.contains(r#"property="og:url""#) {
+            find_attribute(tag, "content")
+        } else {
+            None
+        }
+    })
+}
+
+fn find_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(&format!(r#" {}=""#, name))? + name.len() + 3;
+    let length = tag[start..].find('"')?;
+    Some(&tag[start..start + length])
+}
+
+fn is_page_for_date(page_url: &str, date: NaiveDate) -> bool {
+    let Ok(page_url) = Url::parse(page_url) else {
+        return false;
+    };
+    page_url
+        .path()
+        .trim_end_matches('/')
+        .ends_with(&date.format("/%Y/%m/%d").to_string())
+}
+
+/// Page of comic on source website.
+#[cfg(feature = "serve")]
+pub fn get_source_url(date: NaiveDate) -> String {
+    get_page_url(None, PAGE_BASE_URL, date)
+}
+
 fn get_page_url(proxy: Option<&Url>, base_url: &str, date: NaiveDate) -> String {
     let mut url = String::new();
     if let Some(proxy) = proxy {
@@ -189,44 +503,117 @@ fn save_image(
     bytes: Bytes,
     directory: impl AsRef<Path>,
     image_format: ImageFormat,
-) -> Result<()> {
+) -> Result<PathBuf> {
     let filename = format!("{}.{}", date.format("%Y-%m-%d"), image_format);
-    let path = directory.as_ref().join(filename);
+    let path = directory.as_ref().join(&filename);
+    // Interrupted writes must never leave a file which looks complete
+    let temp_path = directory
+        .as_ref()
+        .join(format!("{}.{}", filename, PARTIAL_EXTENSION));
 
     if image_format == ImageFormat::Gif {
-        fs::write(path, &bytes)?;
+        fs::write(&temp_path, &bytes)?;
     } else {
         let image = image::load_from_memory(&bytes).with_context(|| "loading image from bytes")?;
-        image.save(path)?;
+        image.save_with_format(&temp_path, image::ImageFormat::Png)?;
     }
-    Ok(())
-}
-
-async fn fetch_text(client: &Client, url: Url) -> Result<String> {
-    // TODO(feat): Add error contexts
-    fetch_response(client, url)
-        .await?
-        .text()
-        .await
-        .with_context(|| "...")
+    fs::rename(&temp_path, &path).with_context(|| "moving completed image file")?;
+    debug!(path = %path.display(), "saved image");
+    Ok(path)
 }
 
 async fn fetch_bytes(client: &Client, url: Url) -> Result<Bytes> {
-    // TODO(feat): Add error contexts
-    fetch_response(client, url)
-        .await?
-        .bytes()
-        .await
-        .with_context(|| "...")
+    #[cfg(feature = "metrics")]
+    let started = std::time::Instant::now();
+    let result = async {
+        let bytes = fetch_response(client, url)
+            .await?
+            .bytes()
+            .await
+            .with_context(|| "reading response body")?;
+        debug!(bytes = bytes.len(), "received image data");
+        Ok(bytes)
+    }
+    .await;
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_request(Stage::FetchImage, started.elapsed(), &result);
+    result
 }
 
 async fn fetch_response(client: &Client, url: Url) -> Result<reqwest::Response> {
-    // TODO(feat): Add error contexts
-    client
+    debug!(url = %url, "sending request");
+    let response = client
         .get(url)
         .send()
         .await
-        .with_context(|| "...")?
+        .with_context(|| "sending request")?;
+    debug!(url = %response.url(), status = %response.status(), "received response");
+    response
         .error_for_status()
-        .with_context(|| "...")
+        .with_context(|| "bad response status")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> NaiveDate {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn find_canonical_url_in_link_or_meta() {
+        let body = r#"<html><head><meta charset="utf-8">
+            <link rel="canonical" href="https://www.gocomics.com/garfield/2000/01/02">
+            <meta property="og:url" content="https://www.gocomics.com/garfield/2000/01/03">"#;
+        assert_eq!(
+            find_canonical_url(body),
+            Some("https://www.gocomics.com/garfield/2000/01/02")
+        );
+        let body =
+            r#"<meta property="og:url" content="https://www.gocomics.com/garfield/2000/01/03" />"#;
+        assert_eq!(
+            find_canonical_url(body),
+            Some("https://www.gocomics.com/garfield/2000/01/03")
+        );
+        assert_eq!(
+            find_canonical_url(r#"<a href="/garfield/2000/01/01">"#),
+            None
+        );
+    }
+
+    #[test]
+    fn page_for_date_compares_whole_date() {
+        let page_date = date("2000-01-02");
+        assert!(is_page_for_date(
+            "https://www.gocomics.com/garfield/2000/01/02",
+            page_date
+        ));
+        assert!(is_page_for_date(
+            "https://www.gocomics.com/garfield/2000/01/02/",
+            page_date
+        ));
+        assert!(!is_page_for_date(
+            "https://www.gocomics.com/garfield/2000/01/01",
+            page_date
+        ));
+        assert!(!is_page_for_date(
+            "https://www.gocomics.com/garfield/2000/01/022",
+            page_date
+        ));
+        assert!(!is_page_for_date("not a url", page_date));
+    }
+
+    #[test]
+    fn only_recent_dates_are_not_published() {
+        let latest = dates::latest();
+        let error = missing_page_error(latest + chrono::Duration::days(1), "page not found");
+        assert!(is_not_published(&error));
+        let error = missing_page_error(latest - chrono::Duration::days(1), "page not found");
+        assert!(is_not_published(&error));
+
+        let error = missing_page_error(date("2000-01-01"), "page not found");
+        assert!(!is_not_published(&error));
+        assert!(is_missing_from_source(&error));
+    }
 }
@@ -0,0 +1,333 @@
+//! User commands, run after each saved image and after a completed run.
+//!
+//! Each command is run by the system shell, and receives the event as `EVERYGARF_*` environment
+//! variables, and as a single line of JSON on stdin.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result, bail};
+use chrono::NaiveDate;
+use everygarf::ImageFormat;
+use reqwest::Url;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt as _;
+use tokio::process::Command;
+
+use crate::io::DATA_DIRECTORY_NAME;
+
+/// Output of all commands is appended here, inside data directory.
+const LOG_FILE_NAME: &str = "hooks.log";
+/// Commands still running after this long are killed.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Hooks {
+    on_save: Vec<String>,
+    on_complete: Vec<String>,
+    log_path: PathBuf,
+    timeout: Duration,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum HookEvent<'a> {
+    Save {
+        date: NaiveDate,
+        path: &'a Path,
+        url: &'a Url,
+        format: ImageFormat,
+    },
+    Complete {
+        directory: &'a Path,
+        /// Dates saved by this run.
+        dates: Vec<NaiveDate>,
+    },
+}
+
+impl Hooks {
+    /// Returns `None` if there are no commands to run.
+    pub fn new(
+        on_save: Vec<String>,
+        on_complete: Vec<String>,
+        directory: impl AsRef<Path>,
+    ) -> Option<Self> {
+        if on_save.is_empty() && on_complete.is_empty() {
+            return None;
+        }
+        let log_path = directory
+            .as_ref()
+            .join(DATA_DIRECTORY_NAME)
+            .join(LOG_FILE_NAME);
+        Some(Self {
+            on_save,
+            on_complete,
+            log_path,
+            timeout: TIMEOUT,
+        })
+    }
+
+    /// Run all commands for event, in order.
+    /// Returns the first error, after all commands have run.
+    pub async fn run(&self, event: &HookEvent<'_>) -> Result<()> {
+        let commands = match event {
+            HookEvent::Save { .. } => &self.on_save,
+            HookEvent::Complete { .. } => &self.on_complete,
+        };
+        let mut result = Ok(());
+        for command in commands {
+            let command_result = self.run_command(command, event).await;
+            if let Err(error) = &command_result {
+                self.write_log(&format!("everygarf: {:#}\n", error));
+            }
+            result = result.and(command_result);
+        }
+        result
+    }
+
+    async fn run_command(&self, command: &str, event: &HookEvent<'_>) -> Result<()> {
+        let json = serde_json::to_string(event).expect("Hook event should always serialize");
+        let log = self.open_log()?;
+
+        let mut child = shell_command(command)
+            .envs(event.env_vars())
+            .stdin(Stdio::piped())
+            .stdout(log.try_clone().with_context(|| "opening hook log file")?)
+            .stderr(log)
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("failed to start hook `{}`", command))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // Command is not required to read stdin
+            let _ = stdin.write_all(format!("{}\n", json).as_bytes()).await;
+        }
+
+        let status = tokio::time::timeout(self.timeout, child.wait())
+            .await
+            .with_context(|| format!("hook `{}` timed out", command))?
+            .with_context(|| format!("failed to wait for hook `{}`", command))?;
+        if !status.success() {
+            bail!("hook `{}` failed ({})", command, status);
+        }
+        Ok(())
+    }
+
+    fn open_log(&self) -> Result<File> {
+        if let Some(parent) = self.log_path.parent() {
+            std::fs::create_dir_all(parent).with_context(|| "creating data directory")?;
+        }
+        File::options()
+            .create(true)
+            .append(true)
+            .open(&self.log_path)
+            .with_context(|| "opening hook log file")
+    }
+
+    fn write_log(&self, message: &str) {
+        use std::io::Write as _;
+        // Nowhere else to report this
+        if let Ok(mut log) = self.open_log() {
+            let _ = log.write_all(message.as_bytes());
+        }
+    }
+}
+
+impl HookEvent<'_> {
+    fn env_vars(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Self::Save {
+                date,
+                path,
+                url,
+                format,
+            } => vec![
+                ("EVERYGARF_EVENT", "save".to_string()),
+                ("EVERYGARF_DATE", date.to_string()),
+                ("EVERYGARF_PATH", path.display().to_string()),
+                ("EVERYGARF_URL", url.to_string()),
+                ("EVERYGARF_FORMAT", format.to_string()),
+            ],
+            Self::Complete { directory, dates } => vec![
+                ("EVERYGARF_EVENT", "complete".to_string()),
+                ("EVERYGARF_DIRECTORY", directory.display().to_string()),
+                ("EVERYGARF_COUNT", dates.len().to_string()),
+            ],
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Empty target directory, unique to the test.
+    fn temp_directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "everygarf-test-hooks-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn on_save(directory: &Path, commands: &[&str]) -> Hooks {
+        let commands = commands.iter().map(ToString::to_string).collect();
+        Hooks::new(commands, Vec::new(), directory).unwrap()
+    }
+
+    fn read_log(directory: &Path) -> String {
+        fs::read_to_string(directory.join(DATA_DIRECTORY_NAME).join(LOG_FILE_NAME)).unwrap()
+    }
+
+    #[test]
+    fn no_commands_is_none() {
+        assert!(Hooks::new(Vec::new(), Vec::new(), "directory").is_none());
+    }
+
+    #[tokio::test]
+    async fn save_event_as_env_and_stdin() {
+        let directory = temp_directory("save");
+        let command = format!(
+            "env | grep ^EVERYGARF_ | sort > '{0}/env'; cat > '{0}/stdin'",
+            directory.display()
+        );
+        let hooks = on_save(&directory, &[&command]);
+        let path = directory.join("2000-01-01.png");
+        let url = Url::parse("https://example.com/a.gif").unwrap();
+        let event = HookEvent::Save {
+            date: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            path: &path,
+            url: &url,
+            format: ImageFormat::Png,
+        };
+        hooks.run(&event).await.unwrap();
+
+        let env = fs::read_to_string(directory.join("env")).unwrap();
+        let expected = format!(
+            "EVERYGARF_DATE=2000-01-01\n\
+             EVERYGARF_EVENT=save\n\
+             EVERYGARF_FORMAT=png\n\
+             EVERYGARF_PATH={}\n\
+             EVERYGARF_URL=https://example.com/a.gif\n",
+            path.display()
+        );
+        assert_eq!(env, expected);
+
+        let stdin = fs::read_to_string(directory.join("stdin")).unwrap();
+        assert!(stdin.ends_with('\n'));
+        let json: serde_json::Value = serde_json::from_str(&stdin).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "event": "save",
+                "date": "2000-01-01",
+                "path": path,
+                "url": "https://example.com/a.gif",
+                "format": "png",
+            })
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn complete_event_as_env_and_stdin() {
+        let directory = temp_directory("complete");
+        let command = format!(
+            "env | grep ^EVERYGARF_ | sort > '{0}/env'; cat > '{0}/stdin'",
+            directory.display()
+        );
+        let hooks = Hooks::new(Vec::new(), vec![command], &directory).unwrap();
+        let event = HookEvent::Complete {
+            directory: &directory,
+            dates: vec![
+                NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+                NaiveDate::from_ymd_opt(2000, 1, 2).unwrap(),
+            ],
+        };
+        hooks.run(&event).await.unwrap();
+
+        let env = fs::read_to_string(directory.join("env")).unwrap();
+        let expected = format!(
+            "EVERYGARF_COUNT=2\n\
+             EVERYGARF_DIRECTORY={}\n\
+             EVERYGARF_EVENT=complete\n",
+            directory.display()
+        );
+        assert_eq!(env, expected);
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(directory.join("stdin")).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "event": "complete",
+                "directory": directory,
+                "dates": ["2000-01-01", "2000-01-02"],
+            })
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_command_does_not_stop_others() {
+        let directory = temp_directory("failed");
+        let hooks = on_save(&directory, &["echo first; exit 3", "echo second"]);
+        let path = directory.join("2000-01-01.png");
+        let url = Url::parse("https://example.com/a.gif").unwrap();
+        let event = HookEvent::Save {
+            date: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            path: &path,
+            url: &url,
+            format: ImageFormat::Png,
+        };
+        let error = hooks.run(&event).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("exit status: 3"));
+
+        let log = read_log(&directory);
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines[0], "first");
+        assert!(lines[1].starts_with("everygarf: hook `echo first; exit 3` failed"));
+        assert_eq!(lines[2], "second");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn slow_command_is_killed() {
+        let directory = temp_directory("timeout");
+        let mut hooks = on_save(&directory, &["sleep 10"]);
+        hooks.timeout = Duration::from_millis(100);
+        let path = directory.join("2000-01-01.png");
+        let url = Url::parse("https://example.com/a.gif").unwrap();
+        let event = HookEvent::Save {
+            date: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            path: &path,
+            url: &url,
+            format: ImageFormat::Png,
+        };
+        let started = std::time::Instant::now();
+        let error = hooks.run(&event).await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(error.to_string().contains("timed out"));
+        assert!(read_log(&directory).contains("hook `sleep 10` timed out"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -3,6 +3,11 @@ use std::{fs, io};
 
 use anyhow::{Context as _, Result, bail};
 
+/// Directory inside target directory for config and other non-image files.
+pub const DATA_DIRECTORY_NAME: &str = ".everygarf";
+/// Extension appended to images while being written.
+pub const PARTIAL_EXTENSION: &str = "part";
+
 pub fn get_target_directory() -> Option<PathBuf> {
     const DEFAULT_DIRECTORY_NAME: &str = "garfield";
 
@@ -19,9 +24,52 @@ pub fn create_target_directory(path: impl AsRef<Path>, remove_existing: bool) ->
             bail!(io::Error::from(io::ErrorKind::NotADirectory));
         }
         if !remove_existing {
-            return Ok(());
+            return remove_partial_files(path);
+        }
+        // Keep config and other data
+        for child in fs::read_dir(path).with_context(|| "reading existing directory")? {
+            let child = child?;
+            if child.file_name() == DATA_DIRECTORY_NAME {
+                continue;
+            }
+            if child.file_type()?.is_dir() {
+                fs::remove_dir_all(child.path())
+            } else {
+                fs::remove_file(child.path())
+            }
+            .with_context(|| "removing existing files")?;
         }
-        fs::remove_dir_all(path).with_context(|| "removing existing directory")?;
+        return Ok(());
     }
     fs::create_dir_all(path).with_context(|| "creating empty directory")
 }
+
+/// Remove images left incomplete by an aborted run.
+fn remove_partial_files(path: &Path) -> Result<()> {
+    for child in fs::read_dir(path).with_context(|| "reading existing directory")? {
+        let child = child?.path();
+        if child
+            .extension()
+            .is_some_and(|extension| extension == PARTIAL_EXTENSION)
+        {
+            fs::remove_file(&child).with_context(|| "removing partial file")?;
+        }
+    }
+    Ok(())
+}
+
+/// Write to a temporary file first, so an existing file is never left half-written.
+pub fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut temp_path = PathBuf::from(path);
+    temp_path.as_mut_os_string().push(".tmp");
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)
+}
+
+/// Directory for files which can be safely deleted, such as a local copy of the url cache.
+pub fn get_cache_directory() -> Option<PathBuf> {
+    const CACHE_DIRECTORY_NAME: &str = "everygarf";
+
+    Some(dirs_next::cache_dir()?.join(CACHE_DIRECTORY_NAME))
+}
@@ -0,0 +1,288 @@
+//! Record of the current run in the target directory, so an interrupted run can be resumed
+//! without resolving the same image urls again.
+//!
+//! One event per line, appended as it happens. The latest event of each date wins.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context as _, Result};
+use chrono::NaiveDate;
+use reqwest::Url;
+
+use crate::cache::CacheData;
+use crate::io::{DATA_DIRECTORY_NAME, write_atomic};
+
+const JOURNAL_FILE_NAME: &str = "journal";
+const HEADER: &str = "everygarf-journal 1";
+
+/// State of dates recorded by a previous run which did not complete.
+#[derive(Default)]
+pub struct PreviousRun {
+    pub resolved: CacheData,
+    pub failed: HashSet<NaiveDate>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Event<'a> {
+    Pending(NaiveDate),
+    Started(NaiveDate),
+    Resolved(NaiveDate, &'a Url),
+    Done(NaiveDate),
+    Failed(NaiveDate),
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DateState {
+    Pending,
+    InFlight,
+    Done,
+    Failed,
+}
+
+pub fn get_journal_path(directory: impl AsRef<Path>) -> PathBuf {
+    directory
+        .as_ref()
+        .join(DATA_DIRECTORY_NAME)
+        .join(JOURNAL_FILE_NAME)
+}
+
+/// Missing journal is treated as a previous run which completed.
+pub fn load_previous_run(directory: impl AsRef<Path>) -> Result<PreviousRun> {
+    let path = get_journal_path(directory);
+    if !path.exists() {
+        return Ok(PreviousRun::default());
+    }
+    let text = fs::read_to_string(&path).with_context(|| "reading journal file")?;
+    // Final line is incomplete if run was aborted while writing, even if it still parses
+    let text = match text.rfind('\n') {
+        Some(index) => &text[..=index],
+        None => "",
+    };
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        anyhow::bail!("unsupported journal file format");
+    }
+
+    let mut states = HashMap::new();
+    let mut resolved = CacheData::new();
+    for line in lines {
+        let mut columns = line.split(' ');
+        let (Some(kind), Some(Ok(date))) = (
+            columns.next(),
+            columns.next().map(|date| date.parse::<NaiveDate>()),
+        ) else {
+            continue;
+        };
+        let state = match kind {
+            "pending" => DateState::Pending,
+            "started" => DateState::InFlight,
+            "done" => DateState::Done,
+            "failed" => DateState::Failed,
+            "resolved" => {
+                if let Some(Ok(url)) = columns.next().map(Url::parse) {
+                    resolved.insert(date, url);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        states.insert(date, state);
+    }
+
+    // Dates left pending or in-flight are found again as missing files
+    let failed = states
+        .into_iter()
+        .filter(|(_, state)| *state == DateState::Failed)
+        .map(|(date, _)| date)
+        .collect();
+    Ok(PreviousRun { resolved, failed })
+}
+
+impl Journal {
+    /// Replace any previous journal, keeping resolved urls and failures which are still relevant.
+    pub fn create(
+        directory: impl AsRef<Path>,
+        previous: &PreviousRun,
+        pending_dates: &[NaiveDate],
+    ) -> Result<Self> {
+        let path = get_journal_path(directory);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| "creating data directory")?;
+        }
+
+        let mut contents = String::new();
+        contents += HEADER;
+        contents += "\n";
+        for date in pending_dates {
+            contents += &format_event(Event::Pending(*date));
+            if let Some(url) = previous.resolved.get(date) {
+                contents += &format_event(Event::Resolved(*date, url));
+            }
+        }
+        // Failures which are not retried are kept, so they can still be skipped by the next run
+        let pending: HashSet<_> = pending_dates.iter().collect();
+        for date in previous
+            .failed
+            .iter()
+            .filter(|date| !pending.contains(date))
+        {
+            contents += &format_event(Event::Failed(*date));
+        }
+
+        // Write completely before replacing previous journal
+        write_atomic(&path, contents).with_context(|| "writing journal file")?;
+
+        let file = File::options()
+            .append(true)
+            .open(&path)
+            .with_context(|| "opening journal file")?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Written immediately, so events are kept if the process is killed.
+    pub fn record(&self, event: Event) -> Result<()> {
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(format_event(event).as_bytes())
+            .with_context(|| "writing to journal file")
+    }
+
+    /// Remove journal after a complete run, as there is nothing to resume.
+    pub fn finish(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path).with_context(|| "removing journal file")
+    }
+}
+
+fn format_event(event: Event) -> String {
+    match event {
+        Event::Pending(date) => format!("pending {}\n", date),
+        Event::Started(date) => format!("started {}\n", date),
+        Event::Resolved(date, url) => format!("resolved {} {}\n", date, url),
+        Event::Done(date) => format!("done {}\n", date),
+        Event::Failed(date) => format!("failed {}\n", date),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty target directory, unique to the test.
+    fn temp_directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "everygarf-test-journal-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn date(text: &str) -> NaiveDate {
+        text.parse().unwrap()
+    }
+
+    fn url(path: &str) -> Url {
+        Url::parse(&format!("https://example.com/{}", path)).unwrap()
+    }
+
+    #[test]
+    fn missing_journal_is_completed_run() {
+        let directory = temp_directory("missing");
+        let previous = load_previous_run(&directory).unwrap();
+        assert!(previous.resolved.is_empty());
+        assert!(previous.failed.is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn replay_latest_event_of_each_date() {
+        let directory = temp_directory("replay");
+        let dates = [date("2000-01-01"), date("2000-01-02"), date("2000-01-03")];
+        let journal = Journal::create(&directory, &PreviousRun::default(), &dates).unwrap();
+        let resolved = url("a");
+        journal.record(Event::Started(dates[0])).unwrap();
+        journal
+            .record(Event::Resolved(dates[0], &resolved))
+            .unwrap();
+        journal.record(Event::Done(dates[0])).unwrap();
+        journal.record(Event::Started(dates[1])).unwrap();
+        journal.record(Event::Failed(dates[1])).unwrap();
+        journal.record(Event::Started(dates[2])).unwrap();
+        journal.record(Event::Failed(dates[2])).unwrap();
+        // Retried within the same run
+        journal.record(Event::Started(dates[2])).unwrap();
+        drop(journal);
+
+        let previous = load_previous_run(&directory).unwrap();
+        assert_eq!(previous.resolved.get(&dates[0]), Some(&resolved));
+        assert_eq!(previous.failed, HashSet::from([dates[1]]));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn ignore_incomplete_final_line() {
+        let directory = temp_directory("incomplete");
+        let path = get_journal_path(&directory);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            &path,
+            format!(
+                "{}\nfailed 2000-01-01\nresolved 2000-01-02 https://exa",
+                HEADER
+            ),
+        )
+        .unwrap();
+
+        let previous = load_previous_run(&directory).unwrap();
+        assert_eq!(previous.failed, HashSet::from([date("2000-01-01")]));
+        assert!(previous.resolved.is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn reject_unknown_format() {
+        let directory = temp_directory("format");
+        let path = get_journal_path(&directory);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "everygarf-journal 99\n").unwrap();
+        assert!(load_previous_run(&directory).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn create_keeps_relevant_previous_state() {
+        let directory = temp_directory("create");
+        let previous = PreviousRun {
+            resolved: CacheData::from([(date("2000-01-01"), url("a"))]),
+            failed: HashSet::from([date("2000-01-01"), date("1999-12-31")]),
+        };
+        // Failed date is retried, so only its url is kept
+        let journal = Journal::create(&directory, &previous, &[date("2000-01-01")]).unwrap();
+        drop(journal);
+
+        let replayed = load_previous_run(&directory).unwrap();
+        assert_eq!(replayed.resolved, previous.resolved);
+        assert_eq!(replayed.failed, HashSet::from([date("1999-12-31")]));
+
+        let journal = Journal::create(&directory, &replayed, &[]).unwrap();
+        journal.finish().unwrap();
+        assert!(!get_journal_path(&directory).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
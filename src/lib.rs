@@ -5,6 +5,7 @@ use anyhow::{Context as _, Result};
 use chrono::NaiveDate;
 use clap::ValueEnum;
 use reqwest::Url;
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Copy, Debug, Default, ValueEnum)]
 pub enum Source {
@@ -13,7 +14,8 @@ pub enum Source {
 }
 
 /// Image format (and file extension) to save images as.
-#[derive(Default, Clone, Copy, PartialEq, ValueEnum)]
+#[derive(Default, Clone, Copy, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ImageFormat {
     #[default]
     Gif,
@@ -0,0 +1,72 @@
+//! Diagnostic logs, separate from progress output.
+//!
+//! Logs are printed to stderr only if requested by `-vv` or `RUST_LOG`, so they do not interfere
+//! with the progress display. `--log-file` receives debug logs regardless.
+
+use std::fs::File;
+use std::io::IsTerminal as _;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context as _, Result};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt as _;
+use tracing_subscriber::util::SubscriberInitExt as _;
+use tracing_subscriber::{Layer as _, fmt};
+
+/// Dependencies are only logged if they warn, unless overridden by `RUST_LOG`.
+const FILE_FILTER: &str = "warn,everygarf=debug";
+
+/// `verbose` is the number of `-v` flags. Console is disabled entirely if `quiet`, or if the
+/// terminal is used for something else (interactive mode).
+pub fn init(verbose: u8, quiet: bool, console: bool, log_file: Option<&Path>) -> Result<()> {
+    let console_level = match verbose {
+        0 | 1 => None,
+        2 => Some("debug"),
+        _ => Some("trace"),
+    };
+    let console_filter = if quiet || !console {
+        None
+    } else {
+        // Explicit `RUST_LOG` is always respected on console
+        match std::env::var(EnvFilter::DEFAULT_ENV) {
+            Ok(directives) => Some(EnvFilter::new(directives)),
+            Err(_) => {
+                console_level.map(|level| EnvFilter::new(format!("warn,everygarf={}", level)))
+            }
+        }
+    };
+    let console_layer = console_filter.map(|filter| {
+        fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+            .with_filter(filter)
+    });
+
+    let file_layer = match log_file {
+        Some(path) => {
+            let file = File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("opening log file `{}`", path.display()))?;
+            let filter =
+                EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(FILE_FILTER));
+            Some(
+                fmt::layer()
+                    .with_writer(Mutex::new(file))
+                    .with_ansi(false)
+                    .with_span_events(FmtSpan::CLOSE)
+                    .with_filter(filter),
+            )
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(console_layer)
+        .with(file_layer)
+        .try_init()
+        .with_context(|| "initializing logger")
+}
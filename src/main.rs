@@ -1,56 +1,202 @@
 #![allow(clippy::uninlined_format_args)]
 
 mod args;
+mod cache;
+mod config;
 mod dates;
+#[cfg(feature = "filetype")]
+mod dedupe;
 mod download;
+mod hooks;
 mod io;
+mod journal;
+mod logging;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod mirror;
+mod pick;
+mod progress;
+mod proxy;
+mod report;
+#[cfg(feature = "serve")]
+mod serve;
+mod shutdown;
 mod state;
+#[cfg(feature = "filetype")]
+mod terminal_image;
+#[cfg(feature = "tui")]
+mod tui;
+mod watch;
+mod webhook;
 // TODO(refactor): Rename
 mod controller;
 
 use std::fs;
+use std::io::Write as _;
 use std::path::Path;
 use std::process::ExitCode;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result, bail};
-use chrono::NaiveDate;
-use clap::Parser;
+use chrono::{DateTime, Datelike as _, Local, NaiveDate};
+use clap::{CommandFactory as _, FromArgMatches as _};
 use controller::Sender;
 use everygarf::{DateUrl, UrlPath};
 use reqwest::Client;
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc;
 
-use crate::args::Args;
-use crate::io::{create_target_directory, get_target_directory};
+use crate::args::{Args, Command};
+use crate::cache::{CacheData, Compression};
+use crate::hooks::{HookEvent, Hooks};
+use crate::io::{create_target_directory, get_target_directory, write_atomic};
+use crate::journal::{Journal, PreviousRun};
+use crate::progress::{ProgressStyle, Verbosity};
+use crate::shutdown::Shutdown;
+use crate::state::{Goal, State, Status};
 
 fn main() -> ExitCode {
-    println!("everygarf");
-    if let Err(error) = run() {
-        println!("failed: {}", error);
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    let config_result = config::apply_config_files(&mut args, &matches);
+
+    // Keep stdout machine-readable
+    let is_machine_readable = args.progress == ProgressStyle::Json
+        || args.writes_urls_to_stdout()
+        || args.command.as_ref().is_some_and(Command::prints_path);
+    let show_banner = !is_machine_readable && args.verbosity() > Verbosity::Quiet;
+
+    if show_banner {
+        println!("everygarf");
+    }
+    if let Err(error) = config_result.and_then(|()| run(args)) {
+        tracing::error!("{:#}", error);
+        if is_machine_readable {
+            eprintln!("failed: {:#}", error);
+        } else {
+            println!("failed: {:#}", error);
+        }
         ExitCode::FAILURE
     } else {
-        println!("done!");
+        if show_banner {
+            println!("done!");
+        }
         ExitCode::SUCCESS
     }
 }
 
-fn run() -> Result<()> {
-    let args = Args::parse();
+fn run(mut args: Args) -> Result<()> {
+    // Interactive display uses the whole terminal
+    #[cfg(feature = "tui")]
+    let console = !args.tui;
+    #[cfg(not(feature = "tui"))]
+    let console = true;
+    logging::init(args.verbose, args.quiet, console, args.log_file.as_deref())
+        .with_context(|| "failed to start logging")?;
 
+    #[cfg(feature = "metrics")]
+    if let Some(address) = args.metrics_listen {
+        metrics::start(address).with_context(|| "failed to start metrics server")?;
+    }
+    match args.command.take() {
+        None => run_download(args),
+        Some(Command::Cache(command)) => mirror::run(command, &args),
+        Some(Command::Watch {
+            directory,
+            give_up_after,
+        }) => watch::run(directory, give_up_after, &args),
+        Some(Command::Today(pick)) => pick::today(pick, &args),
+        Some(Command::Random {
+            start_date,
+            end_date,
+            weekdays,
+            pick,
+        }) => pick::random(start_date, end_date, &weekdays, pick, &args),
+        Some(Command::Show { date, pick }) => pick::show(date, pick, &args),
+        #[cfg(feature = "filetype")]
+        Some(Command::Dedupe {
+            directory,
+            max_distance,
+            queue,
+            queue_similar,
+        }) => dedupe::run(
+            directory.or(args.directory),
+            max_distance,
+            queue,
+            queue_similar,
+        ),
+        #[cfg(feature = "serve")]
+        Some(Command::Serve { directory, listen }) => {
+            serve::run(directory.or(args.directory), listen)
+        }
+    }
+}
+
+/// Webhooks are notified of any failure of the run, not only failures while downloading.
+fn run_download(args: Args) -> Result<()> {
     if let Some(option) = check_unimplemented_args(&args) {
         unimplemented!("option {}", option);
     }
+    // Query-only runs have nothing to report
+    if args.webhooks.is_empty() || args.query {
+        return download(args, &mut None);
+    }
 
-    let directory = match args.directory {
+    let webhooks = args.webhooks.clone();
+    let client = build_client(&args, Duration::from_secs(args.timeout_initial.into()))?;
+    let goal = if args.resolve_only {
+        Goal::Resolve
+    } else {
+        Goal::Save
+    };
+    let mut final_state = None;
+    let result = download(args, &mut final_state);
+
+    // Nothing to report if there was nothing to do
+    if result.is_ok() && final_state.is_none() {
+        return result;
+    }
+    let mut state = final_state.unwrap_or_else(|| State::new(0, goal));
+    if let Err(error) = &result
+        && !matches!(state.status(), Status::Failed | Status::Interrupted)
+    {
+        state.set_failed(error);
+    }
+    Runtime::new().unwrap().block_on(webhook::send_all(
+        &client,
+        &webhooks,
+        &state,
+        result.as_ref().err(),
+    ));
+    result
+}
+
+/// `final_state` is set to the progress of the run, once it has finished.
+fn download(args: Args, final_state: &mut Option<State>) -> Result<()> {
+    let request_timeout_primary = Duration::from_secs(args.timeout_primary.into());
+    let request_timeout_initial = Duration::from_secs(args.timeout_initial.into());
+
+    let client_primary = build_client(&args, request_timeout_primary)?;
+    let client_initial = build_client(&args, request_timeout_initial)?;
+    let proxies = args.proxy_pool();
+    let renderer = args.renderer();
+
+    let directory = match args.directory.clone() {
         Some(directory) => directory,
         None => get_target_directory()
             .with_context(|| "failed to find appropriate target directory path")?,
     };
 
+    let runtime = Runtime::new().unwrap();
+
     let date_start = args.start_date.unwrap_or(dates::FIRST_DATE);
-    let date_end = dates::latest();
+    // Only check source if end date could be the latest comic
+    let date_guess = dates::latest();
+    let date_latest = match args.end_date {
+        Some(date_end) if date_end < date_guess => date_guess,
+        _ => runtime.block_on(download::find_latest_date(&client_initial, &proxies)),
+    };
+    let date_end = args.end_date.unwrap_or(date_latest);
 
     // TODO(refactor): Extract as function
     if date_start < dates::FIRST_DATE {
@@ -62,31 +208,55 @@ fn run() -> Result<()> {
     }
     if date_start > date_end {
         bail!(
-            "Start date ({}) must not be after date of latest comic ({})",
+            "Start date ({}) must not be after end date ({})",
             date_start,
             date_end,
         );
     }
+    // Dates which are not published yet are skipped, and found by a later run
+    let date_end = if date_end > date_latest {
+        renderer.warning(&format!(
+            "end date ({}) is after latest comic ({}); skipping later dates",
+            date_end, date_latest,
+        ));
+        date_latest
+    } else {
+        date_end
+    };
 
-    let request_timeout_primary = Duration::from_secs(args.timeout_primary.into());
-    let request_timeout_initial = Duration::from_secs(args.timeout_initial.into());
-
-    // TODO(refactor): Rename `proxy_url`, `args.proxy_url`, `args.cache_url`
-    let proxy = Some(args.proxy).filter(|_| !args.no_proxy);
+    let saves_images = args.saves_images();
+    let report_options = if !saves_images {
+        None
+    } else {
+        Some(report::Options::new(&args, date_start, date_end))
+    };
 
+    // TODO(refactor): Rename `args.cache_url`
     let cache_url = if args.no_cache {
         None
     } else {
         Some(UrlPath::from(args.cache).with_context(|| "parsing cache url")?)
     };
 
-    create_target_directory(&directory, args.remove_all)
-        .with_context(|| "failed to create/clear target directory")?;
-
-    let existing_dates = get_existing_dates(&directory)?;
+    // All dates are resolved, whether or not images already exist
+    let (existing_dates, previous_run) = if args.resolve_only {
+        (Vec::new(), PreviousRun::default())
+    } else {
+        create_target_directory(&directory, args.remove_all)
+            .with_context(|| "failed to create/clear target directory")?;
+        let previous_run = journal::load_previous_run(&directory)
+            .with_context(|| "failed to load journal of previous run")?;
+        (get_existing_dates(&directory)?, previous_run)
+    };
+    #[cfg(feature = "metrics")]
+    if let Some(&date) = existing_dates.iter().max() {
+        metrics::record_existing_date(date);
+    }
 
     let missing_dates = dates::date_iter(date_start..=date_end)
+        .filter(|date| args.weekdays.is_empty() || args.weekdays.contains(&date.weekday()))
         .filter(|date| !existing_dates.contains(date))
+        .filter(|date| !(args.skip_failed && previous_run.failed.contains(date)))
         .map(|date| DateUrl {
             date,
             image_url: None,
@@ -98,82 +268,268 @@ fn run() -> Result<()> {
         None => missing_dates.collect(),
     };
     let pending_count = pending_dates.len();
+    // Proxies are still disabled mid-run if they fail
+    let should_ping = args.always_ping || pending_count >= args::defaults::PING_MIN_IMAGES;
+
+    let attempted_dates: Vec<_> = pending_dates.iter().map(|date_url| date_url.date).collect();
+    let journal = create_journal(saves_images, &directory, &previous_run, &attempted_dates)?;
+
+    let hooks = if !saves_images {
+        None
+    } else {
+        Hooks::new(args.on_save, args.on_complete, &directory).map(Arc::new)
+    };
+    let started_at = Local::now();
+
+    // Existing dates can still be browsed, even with nothing to do
+    #[cfg(feature = "tui")]
+    if args.tui {
+        // Urls resolved by an interrupted run are not resolved again
+        for date_url in &mut pending_dates {
+            date_url.image_url = previous_run.resolved.get(&date_url.date).cloned();
+        }
+        let session = tui::Session {
+            pending_dates,
+            existing_dates,
+            latest: date_latest,
+            cache_url,
+            should_ping,
+            client_initial,
+            client: client_primary,
+            directory: directory.clone(),
+            job_count: args.job_count,
+            max_attempts: args.max_attempts,
+            image_format: args.image_format,
+            proxies,
+            hooks: hooks.clone(),
+            journal,
+        };
+        let outcome = runtime.block_on(tui::run(session))?;
+        // Nothing was requested, only browsed
+        if outcome.dates.is_empty() {
+            return Ok(());
+        }
+        write_report(
+            &directory,
+            started_at,
+            report_options.as_ref(),
+            &outcome.dates,
+            &outcome.state,
+        );
+        if let Some(hooks) = hooks
+            && outcome.state.status() == Status::Complete
+        {
+            let dates = outcome
+                .dates
+                .iter()
+                .copied()
+                .filter(|date| outcome.state.is_completed(*date))
+                .collect();
+            runtime.block_on(run_complete_hooks(&hooks, &directory, dates));
+        }
+        *final_state = Some(outcome.state);
+        return Ok(());
+    }
 
     if pending_dates.is_empty() {
-        println!("nothing to do!");
+        renderer.nothing_to_do();
         return Ok(());
     }
 
-    let client_primary = Client::builder()
-        .user_agent(&args.user_agent)
-        .timeout(request_timeout_primary)
-        .build()
-        .expect("Failed to build request client (primary). This error should never occur.");
-    let client_initial = Client::builder()
-        .user_agent(&args.user_agent)
-        .timeout(request_timeout_initial)
-        .build()
-        .expect("Failed to build request client (initial). This error should never occur.");
-
-    let (tx, mut rx) = mpsc::channel(args.job_count.into());
-    let tx = Sender::new(tx);
-
-    Runtime::new().unwrap().block_on(async move {
-        // TODO(refactor): Rename task to `worker` in all contexts
-        let downloader_handle = tokio::spawn(async move {
-            // TODO(feat): Skip ping for low image count
-            if download::check_proxy(&tx, &client_initial, proxy.as_ref())
-                .await
-                .is_err()
+    let journal = journal.map(Arc::new);
+    let worker_journal = journal.clone();
+    let worker_hooks = hooks.clone();
+    let run_directory = directory.clone();
+
+    runtime.block_on(async move {
+        let shutdown = Shutdown::listen();
+        let worker_shutdown = shutdown.clone();
+
+        let worker = async move |tx: Sender| {
+            if should_ping
+                && download::check_proxies(&tx, &client_initial, &proxies)
+                    .await
+                    .is_err()
             {
-                return;
+                return CacheData::new();
             };
 
             if args.query {
-                return;
+                return CacheData::new();
             }
 
+            let mut cache_data = CacheData::new();
             if let Some(cache_url) = cache_url {
-                // TODO(feat): Handle error
-                let mut cache_data = download::fetch_cached_urls(&tx, &client_initial, cache_url)
-                    .await
-                    .unwrap();
-                // Assumes `pending_dates` has no duplicates (which it shouldn't)
-                for date_url in &mut pending_dates {
-                    date_url.image_url = cache_data.remove(&date_url.date);
+                match download::fetch_cached_urls(&tx, &client_initial, cache_url).await {
+                    Ok(data) => cache_data = data,
+                    Err(error) => {
+                        tx.send_error(error.context("failed to load url cache"))
+                            .await;
+                        return CacheData::new();
+                    }
                 }
             }
+            // Urls resolved by an interrupted run are not resolved again
+            for date_url in &mut pending_dates {
+                date_url.image_url = cache_data
+                    .get(&date_url.date)
+                    .or_else(|| previous_run.resolved.get(&date_url.date))
+                    .cloned();
+            }
 
-            controller::Downloader {
-                tx,
+            let resolved = controller::Downloader {
+                tx: tx.clone(),
                 pending_dates,
                 client: client_primary,
                 directory,
                 job_count: args.job_count,
                 max_attempts: args.max_attempts,
                 image_format: args.image_format,
-                proxy,
+                proxies,
+                resolve_only: args.resolve_only,
+                journal: worker_journal,
+                hooks: worker_hooks,
+                shutdown: worker_shutdown,
             }
             .download_pending_images()
             .await;
-        });
 
-        if let Err(error) = controller::draw_progress_loop(&mut rx, pending_count).await {
-            downloader_handle.abort();
-            // Wait for any additional messages, to prevent sender panicking
-            while rx.recv().await.is_some() {}
-            return Err(error);
+            if let Some(path) = args.save_cache {
+                let mut cache_data = cache_data;
+                cache_data.extend(resolved.clone());
+                if let Err(error) = save_cache(&path, &cache_data, args.cache_compression) {
+                    tx.send_error(error).await;
+                }
+            }
+            resolved
+        };
+
+        let goal = if args.resolve_only {
+            Goal::Resolve
+        } else {
+            Goal::Save
+        };
+        let outcome = controller::run_with_progress(
+            args.job_count,
+            pending_count,
+            goal,
+            renderer,
+            &shutdown,
+            worker,
+        )
+        .await;
+        write_report(
+            &run_directory,
+            started_at,
+            report_options.as_ref(),
+            &attempted_dates,
+            &outcome.state,
+        );
+        *final_state = Some(outcome.state);
+        let resolved = outcome.result?;
+
+        if args.resolve_only {
+            let bytes = cache::encode_urls(&resolved, args.resolve_format, args.cache_compression)?;
+            match &args.output {
+                Some(path) => fs::write(path, bytes).with_context(|| "failed to write output")?,
+                None => std::io::stdout()
+                    .write_all(&bytes)
+                    .with_context(|| "failed to write output")?,
+            }
         }
 
-        // TODO(feat): Handle better
-        if let Err(error) = downloader_handle.await {
-            panic!("{}", error);
+        // Completed dates are kept, and skipped by the next run
+        if shutdown.is_requested() {
+            bail!("interrupted; run again to resume");
+        }
+        // Worker has finished, so no other references remain
+        if let Some(journal) = journal.and_then(Arc::into_inner) {
+            journal
+                .finish()
+                .with_context(|| "failed to remove journal")?;
         }
 
+        if let Some(hooks) = hooks {
+            let mut dates: Vec<_> = resolved.into_keys().collect();
+            dates.sort();
+            run_complete_hooks(&hooks, &run_directory, dates).await;
+        }
         Ok(())
     })
 }
 
+pub fn build_client(args: &Args, timeout: Duration) -> Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent(&args.user_agent)
+        .timeout(timeout);
+
+    // Environment variables are otherwise respected by default
+    if args.no_env_proxy {
+        builder = builder.no_proxy();
+    }
+    if let Some(forward_proxy) = &args.forward_proxy {
+        let mut proxy = reqwest::Proxy::all(forward_proxy.clone())
+            .with_context(|| "invalid forward proxy url")?;
+        if !args.no_env_proxy {
+            proxy = proxy.no_proxy(reqwest::NoProxy::from_env());
+        }
+        builder = builder.proxy(proxy);
+    }
+
+    Ok(builder
+        .build()
+        .expect("Failed to build request client. This error should never occur."))
+}
+
+/// Report is only written by runs which save images.
+fn write_report(
+    directory: &Path,
+    started_at: DateTime<Local>,
+    options: Option<&report::Options>,
+    dates: &[NaiveDate],
+    state: &State,
+) {
+    if let Some(options) = options
+        && let Err(error) = report::write(directory, started_at, options, dates, state)
+    {
+        eprintln!("warning: failed to write report: {:#}", error);
+    }
+}
+
+/// Images are already saved, so the run is still complete if a command fails.
+async fn run_complete_hooks(hooks: &Hooks, directory: &Path, dates: Vec<NaiveDate>) {
+    let event = HookEvent::Complete { directory, dates };
+    if let Err(error) = hooks.run(&event).await {
+        tracing::warn!("complete hook failed: {:#}", error);
+        eprintln!("warning: on-complete hook failed: {:#}", error);
+    }
+}
+
+/// Runs which do not save images must keep the journal of an interrupted run, so it can still
+/// be resumed.
+fn create_journal(
+    saves_images: bool,
+    directory: impl AsRef<Path>,
+    previous_run: &PreviousRun,
+    attempted_dates: &[NaiveDate],
+) -> Result<Option<Journal>> {
+    if !saves_images || attempted_dates.is_empty() {
+        return Ok(None);
+    }
+    let journal = Journal::create(directory, previous_run, attempted_dates)
+        .with_context(|| "failed to create journal")?;
+    Ok(Some(journal))
+}
+
+fn save_cache(
+    path: impl AsRef<Path>,
+    cache_data: &CacheData,
+    compression: Compression,
+) -> Result<()> {
+    let bytes = cache::encode_cache(cache_data, compression)?;
+    write_atomic(path, bytes).with_context(|| "failed to write cache file")
+}
+
 pub fn get_existing_dates(directory: impl AsRef<Path>) -> Result<Vec<NaiveDate>> {
     let mut dates = Vec::new();
     for child in fs::read_dir(directory)? {
@@ -185,7 +541,7 @@ pub fn get_existing_dates(directory: impl AsRef<Path>) -> Result<Vec<NaiveDate>>
     Ok(dates)
 }
 
-fn get_filename_date(path: impl AsRef<Path>) -> Option<NaiveDate> {
+pub fn get_filename_date(path: impl AsRef<Path>) -> Option<NaiveDate> {
     let stem = path.as_ref().file_stem()?.to_str()?;
     NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok()
 }
@@ -197,8 +553,55 @@ fn check_unimplemented_args(args: &Args) -> Option<&'static str> {
     if args.notify_on_fail {
         return Some("--notify-on-fail");
     }
-    if args.save_cache.is_some() {
-        return Some("--save-cache");
-    }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+
+    /// Empty target directory, unique to the test.
+    fn temp_directory(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "everygarf-test-main-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn query_keeps_journal_of_interrupted_run() {
+        let directory = temp_directory("query-journal");
+        let dates = [NaiveDate::from_ymd_opt(2000, 1, 1).unwrap()];
+        let url = reqwest::Url::parse("https://example.com/a").unwrap();
+        let journal = Journal::create(&directory, &PreviousRun::default(), &dates).unwrap();
+        journal
+            .record(journal::Event::Resolved(dates[0], &url))
+            .unwrap();
+        drop(journal);
+        let journal_path = journal::get_journal_path(&directory);
+        let contents = fs::read(&journal_path).unwrap();
+
+        let previous_run = journal::load_previous_run(&directory).unwrap();
+        let argv = ["everygarf", directory.to_str().unwrap(), "--query"];
+        let args = Args::try_parse_from(argv).unwrap();
+        let journal =
+            create_journal(args.saves_images(), &directory, &previous_run, &dates).unwrap();
+        assert!(journal.is_none());
+        assert_eq!(fs::read(&journal_path).unwrap(), contents);
+
+        let argv = ["everygarf", directory.to_str().unwrap()];
+        let args = Args::try_parse_from(argv).unwrap();
+        let journal =
+            create_journal(args.saves_images(), &directory, &previous_run, &dates).unwrap();
+        assert!(journal.is_some());
+        let previous_run = journal::load_previous_run(&directory).unwrap();
+        assert_eq!(previous_run.resolved.get(&dates[0]), Some(&url));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,432 @@
+//! Prometheus metrics, served over HTTP for the lifetime of the process.
+//!
+//! Counters are fed from the same updates as the progress display. Request latency and failure
+//! kinds are recorded where each request is made, as updates do not carry them.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use axum::Router;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use chrono::{NaiveDate, NaiveTime};
+
+use crate::download::{is_missing_from_source, is_not_published};
+use crate::state::{Stage, Update, UpdateSuccess, UpdateWarning};
+
+/// Upper bounds of request latency buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+static METRICS: OnceLock<Mutex<Metrics>> = OnceLock::new();
+
+#[derive(Default)]
+struct Metrics {
+    urls_resolved: u64,
+    images_saved: u64,
+    downloaded_bytes: u64,
+    retries: BTreeMap<&'static str, u64>,
+    failed_dates: u64,
+    request_failures: BTreeMap<&'static str, u64>,
+    request_latency: BTreeMap<&'static str, Histogram>,
+    latest_date: Option<NaiveDate>,
+}
+
+struct Histogram {
+    /// Count of each bucket in `LATENCY_BUCKETS`, not cumulative.
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+/// Start serving `/metrics` in a background thread, and begin recording.
+pub fn start(address: SocketAddr) -> Result<()> {
+    // Bind before returning, so an unavailable address fails the run
+    let listener =
+        std::net::TcpListener::bind(address).with_context(|| "binding metrics address")?;
+    listener
+        .set_nonblocking(true)
+        .with_context(|| "configuring metrics listener")?;
+    METRICS.get_or_init(Mutex::default);
+
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to create metrics runtime");
+        runtime.block_on(async move {
+            let app = Router::new().route("/metrics", get(handle_metrics));
+            let result = match tokio::net::TcpListener::from_std(listener) {
+                Ok(listener) => axum::serve(listener, app).await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                eprintln!("warning: metrics server failed: {}", error);
+            }
+        });
+    });
+    Ok(())
+}
+
+/// Does nothing unless metrics were started.
+pub fn record(update: Update) {
+    with_metrics(|metrics| metrics.record(update));
+}
+
+/// Record a single request attempt, whether or not it succeeded.
+pub fn record_request<T>(stage: Stage, elapsed: Duration, result: &Result<T>) {
+    let error_kind = result.as_ref().err().map(error_kind);
+    with_metrics(|metrics| metrics.record_request(stage, elapsed, error_kind));
+}
+
+/// Latest date which already exists, before any are saved.
+pub fn record_existing_date(date: NaiveDate) {
+    with_metrics(|metrics| metrics.record_date(date));
+}
+
+fn with_metrics(func: impl FnOnce(&mut Metrics)) {
+    if let Some(metrics) = METRICS.get() {
+        func(&mut metrics.lock().unwrap());
+    }
+}
+
+async fn handle_metrics() -> impl IntoResponse {
+    let body = METRICS
+        .get()
+        .map(|metrics| metrics.lock().unwrap().render())
+        .unwrap_or_default();
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+impl Metrics {
+    fn record(&mut self, update: Update) {
+        match update {
+            Update::Success(UpdateSuccess::FetchUrl { .. }) => self.urls_resolved += 1,
+            Update::Success(UpdateSuccess::FetchImage { bytes, .. }) => {
+                self.downloaded_bytes += bytes;
+            }
+            Update::Success(UpdateSuccess::SaveImage { date }) => {
+                self.images_saved += 1;
+                self.record_date(date);
+            }
+            Update::Warning(UpdateWarning::FetchUrl { .. }) => {
+                *self
+                    .retries
+                    .entry(stage_label(Stage::ResolveUrl))
+                    .or_default() += 1;
+            }
+            Update::Warning(UpdateWarning::FetchImage { .. }) => {
+                *self
+                    .retries
+                    .entry(stage_label(Stage::FetchImage))
+                    .or_default() += 1;
+            }
+            Update::Warning(UpdateWarning::Failed { .. }) => self.failed_dates += 1,
+            _ => (),
+        }
+    }
+
+    fn record_request(
+        &mut self,
+        stage: Stage,
+        elapsed: Duration,
+        error_kind: Option<&'static str>,
+    ) {
+        self.request_latency
+            .entry(stage_label(stage))
+            .or_insert_with(Histogram::new)
+            .observe(elapsed.as_secs_f64());
+        if let Some(error_kind) = error_kind {
+            *self.request_failures.entry(error_kind).or_default() += 1;
+        }
+    }
+
+    fn record_date(&mut self, date: NaiveDate) {
+        if self.latest_date.is_none_or(|latest| date > latest) {
+            self.latest_date = Some(date);
+        }
+    }
+
+    /// Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut text = String::new();
+        write_metric(
+            &mut text,
+            "everygarf_urls_resolved_total",
+            "counter",
+            "Image urls resolved from comic pages.",
+            [("", self.urls_resolved)],
+        );
+        write_metric(
+            &mut text,
+            "everygarf_images_downloaded_total",
+            "counter",
+            "Images downloaded and saved.",
+            [("", self.images_saved)],
+        );
+        write_metric(
+            &mut text,
+            "everygarf_downloaded_bytes_total",
+            "counter",
+            "Bytes of image data downloaded.",
+            [("", self.downloaded_bytes)],
+        );
+        write_metric(
+            &mut text,
+            "everygarf_retries_total",
+            "counter",
+            "Failed attempts which were retried, by stage.",
+            self.retries
+                .iter()
+                .map(|(stage, count)| (format!("stage=\"{}\"", stage), *count)),
+        );
+        write_metric(
+            &mut text,
+            "everygarf_failed_dates_total",
+            "counter",
+            "Dates which failed after all attempts.",
+            [("", self.failed_dates)],
+        );
+        write_metric(
+            &mut text,
+            "everygarf_request_failures_total",
+            "counter",
+            "Failed requests, by kind of error.",
+            self.request_failures
+                .iter()
+                .map(|(kind, count)| (format!("kind=\"{}\"", kind), *count)),
+        );
+
+        let name = "everygarf_request_duration_seconds";
+        let _ = writeln!(text, "# HELP {} Duration of requests, by stage.", name);
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        for (stage, histogram) in &self.request_latency {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    text,
+                    "{}_bucket{{stage=\"{}\",le=\"{}\"}} {}",
+                    name, stage, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                text,
+                "{}_bucket{{stage=\"{}\",le=\"+Inf\"}} {}",
+                name, stage, histogram.count
+            );
+            let _ = writeln!(
+                text,
+                "{}_sum{{stage=\"{}\"}} {}",
+                name, stage, histogram.sum
+            );
+            let _ = writeln!(
+                text,
+                "{}_count{{stage=\"{}\"}} {}",
+                name, stage, histogram.count
+            );
+        }
+
+        if let Some(date) = self.latest_date {
+            let timestamp = date.and_time(NaiveTime::MIN).and_utc().timestamp();
+            write_metric(
+                &mut text,
+                "everygarf_latest_date_timestamp_seconds",
+                "gauge",
+                "Date of latest comic in target directory, as a Unix timestamp.",
+                [("", timestamp)],
+            );
+        }
+        text
+    }
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        // Values above all bounds are only counted in `+Inf`
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|&bound| value <= bound) {
+            self.buckets[index] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Write a metric with one sample per label set. Empty labels are omitted.
+fn write_metric<L: AsRef<str>, V: std::fmt::Display>(
+    text: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (L, V)>,
+) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let labels = labels.as_ref();
+        if labels.is_empty() {
+            let _ = writeln!(text, "{} {}", name, value);
+        } else {
+            let _ = writeln!(text, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn stage_label(stage: Stage) -> &'static str {
+    match stage {
+        Stage::ResolveUrl => "resolve_url",
+        Stage::FetchImage => "fetch_image",
+        Stage::SaveImage => "save_image",
+    }
+}
+
+fn error_kind(error: &anyhow::Error) -> &'static str {
+    if is_not_published(error) {
+        return "not_published";
+    }
+    if is_missing_from_source(error) {
+        return "missing";
+    }
+    for cause in error.chain() {
+        if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+            return if error.is_timeout() {
+                "timeout"
+            } else if error.is_connect() {
+                "connect"
+            } else if error.is_status() {
+                "status"
+            } else if error.is_body() || error.is_decode() {
+                "body"
+            } else {
+                "request"
+            };
+        }
+        if cause.is::<std::io::Error>() {
+            return "io";
+        }
+    }
+    "other"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_exposition_format() {
+        let date = NaiveDate::from_ymd_opt(2000, 1, 2).unwrap();
+        let mut metrics = Metrics::default();
+        metrics.record(Update::Success(UpdateSuccess::FetchUrl { date }));
+        metrics.record(Update::Warning(UpdateWarning::FetchImage {
+            attempt: 0,
+            date,
+        }));
+        metrics.record(Update::Success(UpdateSuccess::FetchImage {
+            date,
+            bytes: 1000,
+        }));
+        metrics.record(Update::Success(UpdateSuccess::SaveImage { date }));
+        metrics.record_request(Stage::ResolveUrl, Duration::from_millis(250), None);
+        metrics.record_request(Stage::FetchImage, Duration::from_millis(500), None);
+        metrics.record_request(Stage::FetchImage, Duration::from_secs(40), Some("timeout"));
+
+        let expected = "\
+# HELP everygarf_urls_resolved_total Image urls resolved from comic pages.
+# TYPE everygarf_urls_resolved_total counter
+everygarf_urls_resolved_total 1
+# HELP everygarf_images_downloaded_total Images downloaded and saved.
+# TYPE everygarf_images_downloaded_total counter
+everygarf_images_downloaded_total 1
+# HELP everygarf_downloaded_bytes_total Bytes of image data downloaded.
+# TYPE everygarf_downloaded_bytes_total counter
+everygarf_downloaded_bytes_total 1000
+# HELP everygarf_retries_total Failed attempts which were retried, by stage.
+# TYPE everygarf_retries_total counter
+everygarf_retries_total{stage=\"fetch_image\"} 1
+# HELP everygarf_failed_dates_total Dates which failed after all attempts.
+# TYPE everygarf_failed_dates_total counter
+everygarf_failed_dates_total 0
+# HELP everygarf_request_failures_total Failed requests, by kind of error.
+# TYPE everygarf_request_failures_total counter
+everygarf_request_failures_total{kind=\"timeout\"} 1
+# HELP everygarf_request_duration_seconds Duration of requests, by stage.
+# TYPE everygarf_request_duration_seconds histogram
+everygarf_request_duration_seconds_bucket{stage=\"fetch_image\",le=\"0.05\"} 0
+everygarf_request_duration_seconds_bucket{stage=\"fetch_image\",le=\"0.1\"} 0
+everygarf_request_duration_seconds_bucket{stage=\"fetch_image\",le=\"0.25\"} 0
+everygarf_request_duration_seconds_bucket{stage=\"fetch_image\",le=\"0.5\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"fetch_image\",le=\"1\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"fetch_image\",le=\"2.5\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"fetch_image\",le=\"5\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"fetch_image\",le=\"10\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"fetch_image\",le=\"30\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"fetch_image\",le=\"+Inf\"} 2
+everygarf_request_duration_seconds_sum{stage=\"fetch_image\"} 40.5
+everygarf_request_duration_seconds_count{stage=\"fetch_image\"} 2
+everygarf_request_duration_seconds_bucket{stage=\"resolve_url\",le=\"0.05\"} 0
+everygarf_request_duration_seconds_bucket{stage=\"resolve_url\",le=\"0.1\"} 0
+everygarf_request_duration_seconds_bucket{stage=\"resolve_url\",le=\"0.25\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"resolve_url\",le=\"0.5\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"resolve_url\",le=\"1\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"resolve_url\",le=\"2.5\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"resolve_url\",le=\"5\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"resolve_url\",le=\"10\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"resolve_url\",le=\"30\"} 1
+everygarf_request_duration_seconds_bucket{stage=\"resolve_url\",le=\"+Inf\"} 1
+everygarf_request_duration_seconds_sum{stage=\"resolve_url\"} 0.25
+everygarf_request_duration_seconds_count{stage=\"resolve_url\"} 1
+# HELP everygarf_latest_date_timestamp_seconds Date of latest comic in target directory, as a Unix timestamp.
+# TYPE everygarf_latest_date_timestamp_seconds gauge
+everygarf_latest_date_timestamp_seconds 946771200
+";
+        assert_eq!(metrics.render(), expected);
+    }
+
+    #[test]
+    fn every_sample_has_help_and_type() {
+        let mut metrics = Metrics::default();
+        metrics.record_request(Stage::SaveImage, Duration::from_millis(1), None);
+        let text = metrics.render();
+        let mut declared = Vec::new();
+        for line in text.lines() {
+            if let Some(rest) = line.strip_prefix("# HELP ") {
+                declared.push(rest.split(' ').next().unwrap().to_string());
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                assert_eq!(rest.split(' ').next(), declared.last().map(String::as_str));
+                continue;
+            }
+            let (name, value) = line.rsplit_once(' ').unwrap();
+            let name = name.split('{').next().unwrap();
+            let family = declared.last().unwrap();
+            assert!(
+                name.starts_with(family.as_str()),
+                "{} outside {}",
+                name,
+                family
+            );
+            value.parse::<f64>().unwrap();
+        }
+    }
+
+    #[test]
+    fn latest_date_only_increases() {
+        let mut metrics = Metrics::default();
+        metrics.record_date(NaiveDate::from_ymd_opt(2000, 1, 2).unwrap());
+        metrics.record_date(NaiveDate::from_ymd_opt(2000, 1, 1).unwrap());
+        assert_eq!(metrics.latest_date, NaiveDate::from_ymd_opt(2000, 1, 2));
+    }
+}
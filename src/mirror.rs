@@ -0,0 +1,232 @@
+use std::fs;
+use std::num::NonZero;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result, bail};
+use everygarf::UrlPath;
+use futures::StreamExt as _;
+use reqwest::Client;
+use tokio::runtime::Runtime;
+
+use crate::args::{Args, CacheCommand};
+use crate::cache::{self, CacheData, Compression};
+use crate::controller::{self, Sender};
+use crate::io::write_atomic;
+use crate::progress::Renderer;
+use crate::proxy::ProxyPool;
+use crate::shutdown::Shutdown;
+use crate::state::Goal;
+use crate::{build_client, dates, download};
+
+pub fn run(command: CacheCommand, args: &Args) -> Result<()> {
+    let client = build_client(args, Duration::from_secs(args.timeout_primary.into()))?;
+
+    match command {
+        CacheCommand::Update {
+            file,
+            output,
+            compression,
+        } => {
+            let options = UpdateOptions {
+                client,
+                job_count: args.job_count,
+                max_attempts: args.max_attempts,
+                proxies: args.proxy_pool(),
+            };
+            let output = output.unwrap_or_else(|| file.clone());
+            update_cache(&file, &output, compression, args.renderer(), options)
+        }
+        CacheCommand::Verify { file, sample } => {
+            let cache_url = UrlPath::from(file).with_context(|| "parsing cache url")?;
+            Runtime::new().unwrap().block_on(verify_cache(
+                client,
+                cache_url,
+                sample,
+                args.job_count,
+                args.renderer(),
+            ))
+        }
+    }
+}
+
+struct UpdateOptions {
+    client: Client,
+    job_count: NonZero<usize>,
+    max_attempts: NonZero<usize>,
+    proxies: ProxyPool,
+}
+
+fn update_cache(
+    file: &Path,
+    output: &Path,
+    compression: Option<Compression>,
+    renderer: Renderer,
+    options: UpdateOptions,
+) -> Result<()> {
+    let bytes = fs::read(file).with_context(|| "failed to read cache file")?;
+    let compression = compression.unwrap_or_else(|| cache::detect_compression(&bytes));
+    let mut cache_data = cache::parse_cached_urls(&bytes)
+        .with_context(|| "malformed cache file")?
+        .entries;
+
+    let date_start = match cache_data.keys().max() {
+        Some(last) => *last + chrono::Duration::days(1),
+        None => dates::FIRST_DATE,
+    };
+    let runtime = Runtime::new().unwrap();
+    let date_end = runtime.block_on(download::find_latest_date(
+        &options.client,
+        &options.proxies,
+    ));
+
+    if date_start > date_end {
+        renderer.message("cache is up to date.");
+        return write_cache(output, &cache_data, compression, &renderer);
+    }
+
+    let pending_dates: Vec<_> = dates::date_iter(date_start..=date_end).collect();
+    let pending_count = pending_dates.len();
+    renderer.message(&format!(
+        "resolving {} dates ({} to {}).",
+        pending_count, date_start, date_end,
+    ));
+
+    let job_count = options.job_count;
+    let progress_renderer = renderer.clone();
+    let (resolved, is_interrupted) = runtime.block_on(async move {
+        let shutdown = Shutdown::listen();
+        let worker_shutdown = shutdown.clone();
+
+        let worker = async move |tx: Sender| {
+            let futures = pending_dates.into_iter().map(|date| {
+                let tx = tx.clone();
+                let options = &options;
+                async move {
+                    let result = download::resolve_image_url(
+                        &tx,
+                        date,
+                        &options.client,
+                        options.max_attempts,
+                        &options.proxies,
+                    )
+                    .await;
+                    match result {
+                        Ok(image_url) => Some((date, image_url)),
+                        Err(error) => {
+                            tx.send_error(error).await;
+                            None
+                        }
+                    }
+                }
+            });
+
+            futures::stream::iter(futures)
+                .take_until(worker_shutdown.requested())
+                .buffer_unordered(options.job_count.into())
+                .filter_map(|result| async move { result })
+                .collect::<CacheData>()
+                .await
+        };
+
+        let resolved = controller::run_with_progress(
+            job_count,
+            pending_count,
+            Goal::Resolve,
+            progress_renderer,
+            &shutdown,
+            worker,
+        )
+        .await
+        .result?;
+        anyhow::Ok((resolved, shutdown.is_requested()))
+    })?;
+
+    if is_interrupted {
+        bail!("interrupted; cache was not updated");
+    }
+
+    // Never write a cache with missing dates, as they would not be resolved by later updates
+    if resolved.len() != pending_count {
+        bail!("some dates could not be resolved; cache was not updated");
+    }
+
+    cache_data.extend(resolved);
+    write_cache(output, &cache_data, compression, &renderer)
+}
+
+fn write_cache(
+    path: &Path,
+    cache_data: &CacheData,
+    compression: Compression,
+    renderer: &Renderer,
+) -> Result<()> {
+    let bytes = cache::encode_cache(cache_data, compression)?;
+    write_atomic(path, bytes).with_context(|| "failed to write cache file")?;
+    renderer.message(&format!(
+        "wrote {} entries to {}.",
+        cache_data.len(),
+        path.display()
+    ));
+    Ok(())
+}
+
+async fn verify_cache(
+    client: Client,
+    cache_url: UrlPath,
+    sample: NonZero<usize>,
+    job_count: NonZero<usize>,
+    renderer: Renderer,
+) -> Result<()> {
+    let cache = download::load_cache(&client, cache_url).await?;
+    if let Some(header) = &cache.header {
+        renderer.message(&format!(
+            "cache format version {}, generated {}, {} entries.",
+            header.version, header.generated, header.count,
+        ));
+    } else {
+        renderer.message(&format!(
+            "legacy cache format, {} entries.",
+            cache.entries.len()
+        ));
+    }
+
+    let mut entries: Vec<_> = cache.entries.into_iter().collect();
+    fastrand::shuffle(&mut entries);
+    entries.truncate(sample.into());
+    entries.sort_by_key(|(date, _)| *date);
+    let sample_count = entries.len();
+
+    let futures = entries.into_iter().map(|(date, image_url)| {
+        let client = &client;
+        async move {
+            let result = download::check_image_url(client, image_url.clone()).await;
+            (date, image_url, result)
+        }
+    });
+    let results: Vec<_> = futures::stream::iter(futures)
+        .buffered(job_count.into())
+        .collect()
+        .await;
+
+    let mut failed_count = 0;
+    for (date, image_url, result) in results {
+        if let Err(error) = result {
+            failed_count += 1;
+            renderer.warning(&format!("{} | {} | {:#}", date, image_url, error));
+        }
+    }
+
+    if failed_count > 0 {
+        bail!(
+            "{} of {} sampled entries are unreachable",
+            failed_count,
+            sample_count
+        );
+    }
+    renderer.message(&format!(
+        "all {} sampled entries are reachable.",
+        sample_count
+    ));
+    Ok(())
+}
@@ -0,0 +1,201 @@
+//! Commands which find a single comic, fetching it if it is not saved yet.
+//!
+//! Path of the image is printed to stdout, so it can be used by scripts.
+
+use std::num::NonZero;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result, bail};
+use chrono::{Datelike as _, Local, NaiveDate, Weekday};
+use everygarf::{DateUrl, ImageFormat};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use crate::args::{Args, PickArgs};
+use crate::controller::Sender;
+use crate::download::{self, DownloadOptions};
+use crate::hooks::Hooks;
+use crate::io::{create_target_directory, get_target_directory};
+use crate::progress::{Verbosity, describe_warning};
+use crate::state::Update;
+use crate::{build_client, dates};
+
+/// Same day of the year as today, in a random year before this one.
+pub fn today(pick: PickArgs, args: &Args) -> Result<()> {
+    let today = Local::now().date_naive();
+    let dates: Vec<_> = (dates::FIRST_DATE.year()..today.year())
+        .filter_map(|year| NaiveDate::from_ymd_opt(year, today.month(), today.day()))
+        .filter(|date| *date >= dates::FIRST_DATE)
+        .collect();
+    let date = pick_random(&dates).with_context(|| "no previous year has a comic for today")?;
+    show(date, pick, args)
+}
+
+/// Random date in range, which may be limited to some days of the week.
+pub fn random(
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+    weekdays: &[Weekday],
+    pick: PickArgs,
+    args: &Args,
+) -> Result<()> {
+    let start = start.unwrap_or(dates::FIRST_DATE).max(dates::FIRST_DATE);
+    let latest = find_latest_date(end.unwrap_or_else(dates::latest), args)?;
+    let end = end.unwrap_or(latest).min(latest);
+    if start > end {
+        bail!(
+            "Start date ({}) must not be after end date ({})",
+            start,
+            end
+        );
+    }
+    let dates: Vec<_> = dates::date_iter(start..=end)
+        .filter(|date| weekdays.is_empty() || weekdays.contains(&date.weekday()))
+        .collect();
+    let date = pick_random(&dates).with_context(|| "no dates match")?;
+    show_published(date, pick, args)
+}
+
+/// Print path of image for date, fetching it first if needed.
+pub fn show(date: NaiveDate, pick: PickArgs, args: &Args) -> Result<()> {
+    let latest = find_latest_date(date, args)?;
+    if date < dates::FIRST_DATE || date > latest {
+        bail!(
+            "Date ({}) must be between first comic ({}) and latest comic ({})",
+            date,
+            dates::FIRST_DATE,
+            latest,
+        );
+    }
+    show_published(date, pick, args)
+}
+
+/// Date must already be known to be published.
+fn show_published(date: NaiveDate, pick: PickArgs, args: &Args) -> Result<()> {
+    let directory = match pick.directory.or_else(|| args.directory.clone()) {
+        Some(directory) => directory,
+        None => get_target_directory()
+            .with_context(|| "failed to find appropriate target directory path")?,
+    };
+
+    let path = match find_saved_image(&directory, date) {
+        Some(path) => path,
+        None => fetch_image(&directory, date, args)
+            .with_context(|| format!("failed to fetch comic for {}", date))?,
+    };
+
+    println!("{}", path.display());
+    #[cfg(feature = "filetype")]
+    if let Some(protocol) = pick.inline {
+        crate::terminal_image::draw(&path, protocol)
+            .with_context(|| "failed to draw image in terminal")?;
+    }
+    if pick.open {
+        open_with_viewer(&path).with_context(|| "failed to open image viewer")?;
+    }
+    Ok(())
+}
+
+/// Only checks source if date could be the latest comic.
+fn find_latest_date(date: NaiveDate, args: &Args) -> Result<NaiveDate> {
+    let date_guess = dates::latest();
+    if date < date_guess {
+        return Ok(date_guess);
+    }
+    let client = build_client(args, Duration::from_secs(args.timeout_initial.into()))?;
+    let proxies = args.proxy_pool();
+    Ok(Runtime::new()
+        .unwrap()
+        .block_on(download::find_latest_date(&client, &proxies)))
+}
+
+fn pick_random(dates: &[NaiveDate]) -> Option<NaiveDate> {
+    if dates.is_empty() {
+        return None;
+    }
+    Some(dates[fastrand::usize(..dates.len())])
+}
+
+/// Image may have been saved in either format.
+fn find_saved_image(directory: &Path, date: NaiveDate) -> Option<PathBuf> {
+    [ImageFormat::Gif, ImageFormat::Png]
+        .into_iter()
+        .map(|format| directory.join(format!("{}.{}", date.format("%Y-%m-%d"), format)))
+        .find(|path| path.is_file())
+}
+
+fn fetch_image(directory: &Path, date: NaiveDate, args: &Args) -> Result<PathBuf> {
+    create_target_directory(directory, false)
+        .with_context(|| "failed to create target directory")?;
+    let client = build_client(args, Duration::from_secs(args.timeout_primary.into()))?;
+    let proxies = args.proxy_pool();
+    let verbosity = args.verbosity();
+    let hooks = Hooks::new(args.on_save.clone(), Vec::new(), directory);
+
+    Runtime::new().unwrap().block_on(async {
+        // Updates are only used to report warnings, on stderr to keep stdout clean
+        let (tx, mut rx) = mpsc::channel(NonZero::<usize>::MIN.into());
+        let tx = Sender::new(tx);
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if let Ok(Update::Warning(warning)) = message
+                    && verbosity == Verbosity::Verbose
+                {
+                    eprintln!("warning: {}", describe_warning(warning));
+                }
+            }
+        });
+
+        let options = DownloadOptions {
+            date_url: DateUrl {
+                date,
+                image_url: None,
+            },
+            client,
+            directory,
+            max_attempts: args.max_attempts,
+            image_format: args.image_format,
+            proxies: &proxies,
+            resolve_only: false,
+            journal: None,
+            hooks: hooks.as_ref(),
+        };
+        download::download_image(&tx, options).await
+    })?;
+
+    find_saved_image(directory, date).with_context(|| "image was not saved")
+}
+
+#[cfg(target_os = "macos")]
+fn viewer_command(path: &Path) -> Command {
+    let mut command = Command::new("open");
+    command.arg(path);
+    command
+}
+
+#[cfg(windows)]
+fn viewer_command(path: &Path) -> Command {
+    let mut command = Command::new("cmd");
+    // Empty title, as `start` treats first quoted argument as the window title
+    command.args(["/C", "start", ""]).arg(path);
+    command
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn viewer_command(path: &Path) -> Command {
+    let mut command = Command::new("xdg-open");
+    command.arg(path);
+    command
+}
+
+fn open_with_viewer(path: &Path) -> Result<()> {
+    let status = viewer_command(path)
+        .status()
+        .with_context(|| "starting viewer")?;
+    if !status.success() {
+        bail!("viewer exited with {}", status);
+    }
+    Ok(())
+}
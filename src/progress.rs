@@ -0,0 +1,656 @@
+use std::fmt::Display;
+use std::io::IsTerminal as _;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{
+    Goal, RECENT_WARNING_COUNT, Stage, State, Status, Update, UpdateSuccess, UpdateWarning,
+};
+
+/// Minimum time between progress lines in `plain` style, unless verbose.
+const PLAIN_LOG_INTERVAL: Duration = Duration::from_secs(5);
+/// Used if terminal width cannot be found.
+const DEFAULT_TERMINAL_WIDTH: usize = 80;
+
+/// How progress is reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressStyle {
+    /// `pretty` if stdout is a terminal, otherwise `plain`.
+    #[default]
+    Auto,
+    /// Progress bar and latest updates, redrawn in place.
+    Pretty,
+    /// Throttled log lines, without escape sequences.
+    Plain,
+    /// One JSON object per line, for every update and error, followed by a summary.
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// Only errors.
+    Quiet,
+    Normal,
+    /// Every update.
+    Verbose,
+}
+
+/// Where progress is written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    /// Used when stdout is reserved for output of the run.
+    Stderr,
+}
+
+#[derive(Clone)]
+pub struct Renderer {
+    style: ProgressStyle,
+    verbosity: Verbosity,
+    stream: Stream,
+    started: Instant,
+    last_log: Option<Instant>,
+}
+
+/// Line of `json` progress output.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonLine<'a> {
+    Start {
+        total: usize,
+    },
+    Success(&'a UpdateSuccess),
+    Warning(&'a UpdateWarning),
+    /// Shutdown requested; no new dates will be started.
+    Stopping,
+    /// Status of a command, outside of any run.
+    Message {
+        message: &'a str,
+        warning: bool,
+    },
+    Error {
+        message: String,
+        /// Underlying errors, outermost first.
+        causes: Vec<String>,
+    },
+    Summary {
+        status: Status,
+        completed: usize,
+        total: usize,
+        warnings: usize,
+        downloaded_bytes: u64,
+        elapsed_secs: f64,
+    },
+}
+
+impl ProgressStyle {
+    /// Resolve `auto` style for the stream progress is written to.
+    pub fn resolve(self, stream: Stream) -> Self {
+        let is_terminal = match stream {
+            Stream::Stdout => std::io::stdout().is_terminal(),
+            Stream::Stderr => std::io::stderr().is_terminal(),
+        };
+        match self {
+            Self::Auto if is_terminal => Self::Pretty,
+            Self::Auto => Self::Plain,
+            style => style,
+        }
+    }
+}
+
+impl Renderer {
+    pub fn new(style: ProgressStyle, verbosity: Verbosity, stream: Stream) -> Self {
+        Self {
+            style: style.resolve(stream),
+            verbosity,
+            stream,
+            started: Instant::now(),
+            last_log: None,
+        }
+    }
+
+    pub fn start(&mut self, state: &mut State) {
+        match self.style {
+            ProgressStyle::Auto => unreachable!("style should be resolved"),
+            _ if self.verbosity == Verbosity::Quiet => (),
+            ProgressStyle::Pretty => self.draw_pretty(state, false),
+            ProgressStyle::Plain => {
+                self.println(format!("started: {} dates.", state.total_units()))
+            }
+            ProgressStyle::Json => self.print_json(&JsonLine::Start {
+                total: state.total_units(),
+            }),
+        }
+    }
+
+    /// Must be called after `state` has been updated.
+    pub fn update(&mut self, state: &mut State, update: Update) {
+        match self.style {
+            ProgressStyle::Auto => unreachable!("style should be resolved"),
+            _ if self.verbosity == Verbosity::Quiet => (),
+            ProgressStyle::Pretty => {
+                // Keep warnings visible above progress display
+                if self.verbosity == Verbosity::Verbose
+                    && let Update::Warning(warning) = update
+                {
+                    if !state.record_draw() {
+                        self.clear_lines(PRETTY_LINE_COUNT);
+                    }
+                    self.println(format!("warning: {}", describe_warning(warning)));
+                    state.reset_draw();
+                }
+                self.draw_pretty(state, false);
+            }
+            ProgressStyle::Plain => self.log_plain(state, update),
+            ProgressStyle::Json => match &update {
+                Update::EnterStage { .. } => (),
+                Update::Success(success) => self.print_json(&JsonLine::Success(success)),
+                Update::Warning(warning) => self.print_json(&JsonLine::Warning(warning)),
+            },
+        }
+    }
+
+    /// Must be called after `state` has been set to stopping.
+    pub fn stopping(&mut self, state: &mut State) {
+        match self.style {
+            ProgressStyle::Auto => unreachable!("style should be resolved"),
+            _ if self.verbosity == Verbosity::Quiet => (),
+            ProgressStyle::Pretty => self.draw_pretty(state, false),
+            ProgressStyle::Plain => {
+                self.println("stopping: waiting for current dates (press Ctrl-C again to abort).")
+            }
+            ProgressStyle::Json => self.print_json(&JsonLine::Stopping),
+        }
+    }
+
+    pub fn fail(&mut self, state: &mut State, error: &anyhow::Error) {
+        match self.style {
+            ProgressStyle::Auto => unreachable!("style should be resolved"),
+            ProgressStyle::Pretty if self.verbosity > Verbosity::Quiet => {
+                self.draw_pretty(state, true)
+            }
+            ProgressStyle::Pretty | ProgressStyle::Plain => (),
+            ProgressStyle::Json => {
+                self.print_json(&JsonLine::Error {
+                    message: error.to_string(),
+                    causes: error.chain().skip(1).map(ToString::to_string).collect(),
+                });
+                // Same as `finish`, so quiet output never has a summary
+                if self.verbosity > Verbosity::Quiet {
+                    self.print_summary(state);
+                }
+            }
+        }
+    }
+
+    pub fn finish(&mut self, state: &mut State) {
+        match self.style {
+            ProgressStyle::Auto => unreachable!("style should be resolved"),
+            _ if self.verbosity == Verbosity::Quiet => (),
+            ProgressStyle::Pretty => self.draw_pretty(state, true),
+            ProgressStyle::Plain => self.println(format!(
+                "{}: {}/{} dates in {:.1}s ({} warnings).",
+                if state.status() == Status::Interrupted {
+                    "interrupted"
+                } else {
+                    "complete"
+                },
+                state.completed_units(),
+                state.total_units(),
+                self.started.elapsed().as_secs_f64(),
+                state.warning_count(),
+            )),
+            ProgressStyle::Json => self.print_summary(state),
+        }
+    }
+
+    /// Status of a command, outside of any run.
+    pub fn message(&self, message: &str) {
+        self.log_message(message, false);
+    }
+
+    pub fn warning(&self, message: &str) {
+        self.log_message(message, true);
+    }
+
+    fn log_message(&self, message: &str, warning: bool) {
+        match self.style {
+            ProgressStyle::Auto => unreachable!("style should be resolved"),
+            _ if self.verbosity == Verbosity::Quiet => (),
+            ProgressStyle::Pretty | ProgressStyle::Plain if warning => {
+                self.println(format!("warning: {}", message))
+            }
+            ProgressStyle::Pretty | ProgressStyle::Plain => self.println(message),
+            ProgressStyle::Json => self.print_json(&JsonLine::Message { message, warning }),
+        }
+    }
+
+    /// Report that no work is required, instead of starting.
+    pub fn nothing_to_do(&self) {
+        match self.style {
+            ProgressStyle::Auto => unreachable!("style should be resolved"),
+            _ if self.verbosity == Verbosity::Quiet => (),
+            ProgressStyle::Pretty | ProgressStyle::Plain => self.println("nothing to do!"),
+            ProgressStyle::Json => {
+                let mut state = State::new(0, Goal::Save);
+                state.update(Update::Success(UpdateSuccess::Complete));
+                self.print_summary(&state);
+            }
+        }
+    }
+
+    fn log_plain(&mut self, state: &State, update: Update) {
+        match update {
+            Update::EnterStage { .. } => return,
+            Update::Warning(warning) => {
+                self.println(format!("warning: {}", describe_warning(warning)))
+            }
+            Update::Success(success) => {
+                let is_prologue = matches!(
+                    success,
+                    UpdateSuccess::ProxyPing | UpdateSuccess::FetchCache { .. }
+                );
+                if is_prologue || self.verbosity == Verbosity::Verbose {
+                    self.println(describe_success(success));
+                }
+            }
+        }
+
+        let should_log = self
+            .last_log
+            .is_none_or(|last_log| last_log.elapsed() >= PLAIN_LOG_INTERVAL);
+        let is_working = matches!(state.status(), Status::Working | Status::Stopping);
+        if should_log && is_working {
+            self.last_log = Some(Instant::now());
+            self.println(format!(
+                "progress: {:.2}% ({}/{}, {}).",
+                state.percent(),
+                state.completed_units(),
+                state.total_units(),
+                describe_rate(state),
+            ));
+        }
+    }
+
+    fn draw_pretty(&self, state: &mut State, concise: bool) {
+        let terminal_width = get_terminal_width(self.stream);
+        // Leave room for percentage, brackets and final column
+        let bar_width = terminal_width.saturating_sub(11).max(10);
+        let percent = state.percent();
+        let bar_progress = (percent / 100.0 * bar_width as f32) as usize;
+
+        let mut lines = Vec::new();
+
+        // Always draw progress bar no matter the context
+        let bar: String = (0..bar_width)
+            .map(|i| if i < bar_progress { '#' } else { '.' })
+            .collect();
+        lines.push(format!("{:6.2}% [{}]", percent, bar));
+        lines.push(format!("   rate: {}", describe_rate(state)));
+
+        if !concise {
+            let status = match state.status() {
+                Status::PingProxy => "pinging proxy server...",
+                Status::FetchCache => "downloading url cache...",
+                Status::Working => "in progress...",
+                Status::Stopping => {
+                    "stopping, waiting for current dates (Ctrl-C again to abort)..."
+                }
+                Status::Complete => "all done.",
+                Status::Interrupted => "interrupted.",
+                Status::Failed => "failed!",
+            };
+            lines.push(format!(" status: {}", status));
+
+            let mut stages = format!("{} resolving url", state.stage_count(Stage::ResolveUrl));
+            if state.goal() == Goal::Save {
+                stages += &format!(
+                    ", {} downloading, {} saving",
+                    state.stage_count(Stage::FetchImage),
+                    state.stage_count(Stage::SaveImage),
+                );
+            }
+            lines.push(format!("   jobs: {}", stages));
+
+            let latest = match state.latest_success() {
+                None => "started.".to_string(),
+                Some(UpdateSuccess::Complete) => {
+                    unreachable!(
+                        "if recieved `Complete` message, display should from now on be `concise`"
+                    );
+                }
+                Some(success) => describe_success(success),
+            };
+            lines.push(format!(" latest: {}", latest));
+
+            // Pad to keep line count constant
+            let mut warnings: Vec<_> = state
+                .recent_warnings()
+                .map(|warning| format!("warning: {}", describe_warning(warning)))
+                .collect();
+            warnings.resize(RECENT_WARNING_COUNT, String::new());
+            lines.extend(warnings);
+        }
+
+        if !state.record_draw() {
+            self.clear_lines(PRETTY_LINE_COUNT);
+        }
+        // Wrapped lines would break clearing on next draw
+        for line in lines {
+            self.println(truncate(&line, terminal_width));
+        }
+    }
+
+    fn print_summary(&self, state: &State) {
+        self.print_json(&JsonLine::Summary {
+            status: state.status(),
+            completed: state.completed_units(),
+            total: state.total_units(),
+            warnings: state.warning_count(),
+            downloaded_bytes: state.downloaded_bytes(),
+            elapsed_secs: self.started.elapsed().as_secs_f64(),
+        });
+    }
+
+    fn print_json(&self, line: &JsonLine) {
+        self.println(serde_json::to_string(line).expect("Progress should always serialize"));
+    }
+
+    fn clear_lines(&self, count: usize) {
+        for _ in 0..count {
+            self.print("\r"); // Move cursor to beginning of line
+            self.print("\x1b[1A"); // Move cursor up
+            self.print("\x1b[2K"); // Clear entire line
+        }
+    }
+
+    fn print(&self, text: impl Display) {
+        match self.stream {
+            Stream::Stdout => print!("{}", text),
+            Stream::Stderr => eprint!("{}", text),
+        }
+    }
+
+    fn println(&self, line: impl Display) {
+        match self.stream {
+            Stream::Stdout => println!("{}", line),
+            Stream::Stderr => eprintln!("{}", line),
+        }
+    }
+}
+
+const PRETTY_LINE_COUNT: usize = 5 + RECENT_WARNING_COUNT;
+
+/// Other stream may be redirected, even when progress is written to a terminal.
+fn get_terminal_width(stream: Stream) -> usize {
+    let size = match stream {
+        Stream::Stdout => terminal_size::terminal_size_of(std::io::stdout()),
+        Stream::Stderr => terminal_size::terminal_size_of(std::io::stderr()),
+    };
+    size.map(|(width, _)| usize::from(width.0))
+        .unwrap_or(DEFAULT_TERMINAL_WIDTH)
+}
+
+fn truncate(line: &str, width: usize) -> &str {
+    match line.char_indices().nth(width.saturating_sub(1)) {
+        Some((index, _)) => &line[..index],
+        None => line,
+    }
+}
+
+pub fn describe_success(success: UpdateSuccess) -> String {
+    match success {
+        UpdateSuccess::ProxyPing => "proxy server working.".to_string(),
+        UpdateSuccess::FetchCache { generated } => match generated {
+            Some(generated) => format!("downloaded url cache (generated {}).", generated),
+            None => "downloaded url cache.".to_string(),
+        },
+        UpdateSuccess::FetchUrl { date } => format!("{} | fetched image url.", date),
+        UpdateSuccess::FetchImage { date, bytes } => {
+            format!(
+                "{} | downloaded image ({}).",
+                date,
+                format_bytes(bytes as f64)
+            )
+        }
+        UpdateSuccess::SaveImage { date } => format!("{} | saved image.", date),
+        UpdateSuccess::Complete => "all done.".to_string(),
+    }
+}
+
+pub fn describe_rate(state: &State) -> String {
+    let mut rate = match state.goal() {
+        Goal::Resolve => format!("{:.1} urls/s", state.unit_rate()),
+        Goal::Save => format!(
+            "{:.1} images/s, {}/s, {} total",
+            state.unit_rate(),
+            format_bytes(state.byte_rate()),
+            format_bytes(state.downloaded_bytes() as f64),
+        ),
+    };
+    match state.eta() {
+        Some(eta) if state.status() == Status::Working => {
+            rate += &format!(" | eta: {}", format_duration(eta));
+        }
+        _ => rate += &format!(" | elapsed: {}", format_duration(state.working_elapsed())),
+    }
+    rate
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, secs) => format!("{}s", secs),
+        (0, mins, secs) => format!("{}m {:02}s", mins, secs),
+        (hours, mins, secs) => format!("{}h {:02}m {:02}s", hours, mins, secs),
+    }
+}
+
+pub fn describe_warning(warning: UpdateWarning) -> String {
+    match warning {
+        UpdateWarning::FetchUrl { attempt, date } => format!(
+            "{} | failed to fetch image url (attempt {}).",
+            date,
+            attempt + 1,
+        ),
+        UpdateWarning::FetchImage { attempt, date } => format!(
+            "{} | failed to download image (attempt {}).",
+            date,
+            attempt + 1,
+        ),
+        UpdateWarning::Failed { date } => format!("{} | failed.", date),
+        UpdateWarning::HookFailed { date } => {
+            format!("{} | hook failed (see hooks.log in data directory).", date)
+        }
+        UpdateWarning::NotPublished { date } => {
+            format!("{} | not published yet, skipped.", date)
+        }
+        UpdateWarning::DisableProxy { index } => {
+            format!("disabled proxy #{} after failed requests.", index + 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use serde_json::json;
+
+    use super::*;
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2000, 1, 2).unwrap()
+    }
+
+    fn to_json(line: &JsonLine) -> serde_json::Value {
+        serde_json::to_value(line).unwrap()
+    }
+
+    #[test]
+    fn start_and_stopping() {
+        assert_eq!(
+            to_json(&JsonLine::Start { total: 3 }),
+            json!({"type": "start", "total": 3}),
+        );
+        assert_eq!(to_json(&JsonLine::Stopping), json!({"type": "stopping"}));
+    }
+
+    #[test]
+    fn success_events() {
+        let cases = [
+            (UpdateSuccess::ProxyPing, json!({"event": "proxy_ping"})),
+            (
+                UpdateSuccess::FetchCache {
+                    generated: Some(date()),
+                },
+                json!({"event": "fetch_cache", "generated": "2000-01-02"}),
+            ),
+            (
+                UpdateSuccess::FetchCache { generated: None },
+                json!({"event": "fetch_cache", "generated": null}),
+            ),
+            (
+                UpdateSuccess::FetchUrl { date: date() },
+                json!({"event": "fetch_url", "date": "2000-01-02"}),
+            ),
+            (
+                UpdateSuccess::FetchImage {
+                    date: date(),
+                    bytes: 1234,
+                },
+                json!({"event": "fetch_image", "date": "2000-01-02", "bytes": 1234}),
+            ),
+            (
+                UpdateSuccess::SaveImage { date: date() },
+                json!({"event": "save_image", "date": "2000-01-02"}),
+            ),
+            (UpdateSuccess::Complete, json!({"event": "complete"})),
+        ];
+        for (success, mut expected) in cases {
+            expected["type"] = json!("success");
+            assert_eq!(to_json(&JsonLine::Success(&success)), expected);
+        }
+    }
+
+    #[test]
+    fn warning_events() {
+        let cases = [
+            (
+                UpdateWarning::FetchUrl {
+                    attempt: 0,
+                    date: date(),
+                },
+                json!({"event": "fetch_url", "attempt": 1, "date": "2000-01-02"}),
+            ),
+            (
+                UpdateWarning::FetchImage {
+                    attempt: 2,
+                    date: date(),
+                },
+                json!({"event": "fetch_image", "attempt": 3, "date": "2000-01-02"}),
+            ),
+            (
+                UpdateWarning::DisableProxy { index: 1 },
+                json!({"event": "disable_proxy", "index": 1}),
+            ),
+            (
+                UpdateWarning::Failed { date: date() },
+                json!({"event": "failed", "date": "2000-01-02"}),
+            ),
+            (
+                UpdateWarning::HookFailed { date: date() },
+                json!({"event": "hook_failed", "date": "2000-01-02"}),
+            ),
+            (
+                UpdateWarning::NotPublished { date: date() },
+                json!({"event": "not_published", "date": "2000-01-02"}),
+            ),
+        ];
+        for (warning, mut expected) in cases {
+            expected["type"] = json!("warning");
+            assert_eq!(to_json(&JsonLine::Warning(&warning)), expected);
+        }
+    }
+
+    #[test]
+    fn message() {
+        assert_eq!(
+            to_json(&JsonLine::Message {
+                message: "verified",
+                warning: false,
+            }),
+            json!({"type": "message", "message": "verified", "warning": false}),
+        );
+    }
+
+    #[test]
+    fn error_with_causes() {
+        let error = anyhow::anyhow!("connection reset")
+            .context("fetching page")
+            .context("failed to resolve url");
+        let line = JsonLine::Error {
+            message: error.to_string(),
+            causes: error.chain().skip(1).map(ToString::to_string).collect(),
+        };
+        assert_eq!(
+            to_json(&line),
+            json!({
+                "type": "error",
+                "message": "failed to resolve url",
+                "causes": ["fetching page", "connection reset"],
+            }),
+        );
+    }
+
+    #[test]
+    fn summary() {
+        let line = JsonLine::Summary {
+            status: Status::Interrupted,
+            completed: 2,
+            total: 5,
+            warnings: 1,
+            downloaded_bytes: 4096,
+            elapsed_secs: 1.5,
+        };
+        assert_eq!(
+            to_json(&line),
+            json!({
+                "type": "summary",
+                "status": "interrupted",
+                "completed": 2,
+                "total": 5,
+                "warnings": 1,
+                "downloaded_bytes": 4096,
+                "elapsed_secs": 1.5,
+            }),
+        );
+    }
+
+    #[test]
+    fn lines_are_single_line() {
+        let line = JsonLine::Message {
+            message: "first\nsecond",
+            warning: true,
+        };
+        let text = serde_json::to_string(&line).unwrap();
+        assert!(!text.contains('\n'));
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use clap::ValueEnum;
+use reqwest::Url;
+use serde::Deserialize;
+
+/// Consecutive failed requests before a proxy is disabled for the rest of the run.
+const MAX_CONSECUTIVE_FAILURES: usize = 5;
+
+/// How to choose between multiple proxies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyStrategy {
+    /// Use the first working proxy, moving to the next when it is disabled.
+    #[default]
+    Failover,
+    /// Spread requests over all working proxies.
+    RoundRobin,
+}
+
+/// Set of proxies shared by all jobs.
+///
+/// When no proxies are enabled (none given, or all disabled), requests are made directly.
+pub struct ProxyPool {
+    proxies: Vec<Proxy>,
+    strategy: ProxyStrategy,
+    next: AtomicUsize,
+}
+
+struct Proxy {
+    url: Url,
+    consecutive_failures: AtomicUsize,
+    disabled: AtomicBool,
+}
+
+impl ProxyPool {
+    pub fn new(urls: Vec<Url>, strategy: ProxyStrategy) -> Self {
+        let proxies = urls
+            .into_iter()
+            .map(|url| Proxy {
+                url,
+                consecutive_failures: AtomicUsize::new(0),
+                disabled: AtomicBool::new(false),
+            })
+            .collect();
+        Self {
+            proxies,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pool with no proxies, for direct requests only.
+    pub fn direct() -> Self {
+        Self::new(Vec::new(), ProxyStrategy::default())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proxies.is_empty()
+    }
+
+    /// Iterate all proxies (including disabled), with indices.
+    pub fn urls(&self) -> impl Iterator<Item = (usize, &Url)> {
+        self.proxies.iter().map(|proxy| &proxy.url).enumerate()
+    }
+
+    /// Returns `None` if requests should be made directly.
+    pub fn pick(&self) -> Option<(usize, &Url)> {
+        let count = self.proxies.len();
+        let offset = match self.strategy {
+            ProxyStrategy::Failover => 0,
+            ProxyStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        (0..count)
+            .map(|i| offset.wrapping_add(i) % count)
+            .find(|&index| !self.proxies[index].disabled.load(Ordering::Relaxed))
+            .map(|index| (index, &self.proxies[index].url))
+    }
+
+    /// Record result of a request made through a proxy.
+    /// Returns `true` if this failure caused the proxy to be disabled.
+    pub fn report(&self, index: usize, success: bool) -> bool {
+        let proxy = &self.proxies[index];
+        if success {
+            proxy.consecutive_failures.store(0, Ordering::Relaxed);
+            return false;
+        }
+        let failures = proxy.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        failures >= MAX_CONSECUTIVE_FAILURES && self.disable(index)
+    }
+
+    /// Returns `true` if proxy was not already disabled.
+    pub fn disable(&self, index: usize) -> bool {
+        !self.proxies[index].disabled.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn enabled_count(&self) -> usize {
+        self.proxies
+            .iter()
+            .filter(|proxy| !proxy.disabled.load(Ordering::Relaxed))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(count: usize, strategy: ProxyStrategy) -> ProxyPool {
+        let urls = (0..count)
+            .map(|i| Url::parse(&format!("https://proxy{}.example/", i)).unwrap())
+            .collect();
+        ProxyPool::new(urls, strategy)
+    }
+
+    fn picked_index(pool: &ProxyPool) -> Option<usize> {
+        pool.pick().map(|(index, _)| index)
+    }
+
+    #[test]
+    fn empty_pool_is_direct() {
+        let pool = ProxyPool::direct();
+        assert!(pool.is_empty());
+        assert_eq!(picked_index(&pool), None);
+    }
+
+    #[test]
+    fn failover_uses_first_enabled() {
+        let pool = pool(3, ProxyStrategy::Failover);
+        assert_eq!(picked_index(&pool), Some(0));
+        assert_eq!(picked_index(&pool), Some(0));
+        assert!(pool.disable(0));
+        assert_eq!(picked_index(&pool), Some(1));
+        assert!(pool.disable(1));
+        assert!(pool.disable(2));
+        assert_eq!(picked_index(&pool), None);
+        assert_eq!(pool.enabled_count(), 0);
+    }
+
+    #[test]
+    fn round_robin_skips_disabled() {
+        let pool = pool(3, ProxyStrategy::RoundRobin);
+        let picked: Vec<_> = (0..4).filter_map(|_| picked_index(&pool)).collect();
+        assert_eq!(picked, [0, 1, 2, 0]);
+
+        pool.disable(1);
+        let picked: Vec<_> = (0..4).filter_map(|_| picked_index(&pool)).collect();
+        assert!(picked.iter().all(|&index| index != 1));
+        assert!(picked.contains(&0) && picked.contains(&2));
+    }
+
+    #[test]
+    fn disable_after_consecutive_failures() {
+        let pool = pool(2, ProxyStrategy::Failover);
+        for _ in 0..MAX_CONSECUTIVE_FAILURES - 1 {
+            assert!(!pool.report(0, false));
+        }
+        // Success resets the count
+        assert!(!pool.report(0, true));
+        for _ in 0..MAX_CONSECUTIVE_FAILURES - 1 {
+            assert!(!pool.report(0, false));
+        }
+        assert_eq!(pool.enabled_count(), 2);
+
+        assert!(pool.report(0, false));
+        assert_eq!(pool.enabled_count(), 1);
+        assert_eq!(picked_index(&pool), Some(1));
+        // Already disabled
+        assert!(!pool.report(0, false));
+        assert!(!pool.disable(0));
+    }
+}
@@ -0,0 +1,478 @@
+//! Record of each run, written as JSON and as text into the target directory.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Local, NaiveDate, Weekday};
+use everygarf::ImageFormat;
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::args::Args;
+use crate::io::DATA_DIRECTORY_NAME;
+use crate::progress::{describe_warning, format_bytes, format_duration};
+use crate::state::{Stage, State, Status, UpdateWarning};
+
+const REPORTS_DIRECTORY_NAME: &str = "reports";
+const VERSION: u32 = 1;
+
+/// Options which affect which dates are attempted, and how.
+#[derive(Clone, Serialize)]
+pub struct Options {
+    start: NaiveDate,
+    end: NaiveDate,
+    weekdays: Vec<Weekday>,
+    max_images: Option<usize>,
+    jobs: usize,
+    attempts: usize,
+    timeout_secs: u64,
+    format: ImageFormat,
+    proxies: Vec<Url>,
+    cache: Option<PathBuf>,
+    skip_failed: bool,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    version: u32,
+    started_at: DateTime<Local>,
+    status: Status,
+    options: &'a Options,
+    duration_secs: f64,
+    downloaded_bytes: u64,
+    completed: usize,
+    total: usize,
+    stages: Vec<StageReport>,
+    dates: Vec<DateReport<'a>>,
+    /// Error which stopped the run, outermost context first.
+    error: Option<ErrorReport<'a>>,
+}
+
+#[derive(Serialize)]
+struct StageReport {
+    stage: Stage,
+    count: usize,
+    total_secs: f64,
+    average_secs: f64,
+}
+
+#[derive(Serialize)]
+struct DateReport<'a> {
+    date: NaiveDate,
+    status: DateStatus,
+    retries: usize,
+    warnings: &'a [UpdateWarning],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum DateStatus {
+    Completed,
+    Failed,
+    NotPublished,
+    /// Run stopped before date was finished.
+    Unfinished,
+}
+
+#[derive(Serialize)]
+struct ErrorReport<'a> {
+    date: Option<NaiveDate>,
+    chain: &'a [String],
+}
+
+impl Options {
+    pub fn new(args: &Args, start: NaiveDate, end: NaiveDate) -> Self {
+        Self {
+            start,
+            end,
+            weekdays: args.weekdays.clone(),
+            max_images: args.max_images.map(Into::into),
+            jobs: args.job_count.into(),
+            attempts: args.max_attempts.into(),
+            timeout_secs: args.timeout_primary.into(),
+            format: args.image_format,
+            proxies: if args.no_proxy {
+                Vec::new()
+            } else {
+                args.proxy.clone()
+            },
+            cache: (!args.no_cache).then(|| args.cache.clone()),
+            skip_failed: args.skip_failed,
+        }
+    }
+}
+
+/// Write report of run as `<time>.json` and `<time>.txt`. Returns path of JSON report.
+pub fn write(
+    directory: impl AsRef<Path>,
+    started_at: DateTime<Local>,
+    options: &Options,
+    dates: &[NaiveDate],
+    state: &State,
+) -> Result<PathBuf> {
+    let report = Report::new(started_at, options, dates, state);
+
+    let reports_directory = directory
+        .as_ref()
+        .join(DATA_DIRECTORY_NAME)
+        .join(REPORTS_DIRECTORY_NAME);
+    fs::create_dir_all(&reports_directory).with_context(|| "creating reports directory")?;
+
+    let stem = started_at.format("%Y-%m-%dT%H-%M-%S").to_string();
+    let json_path = reports_directory.join(format!("{}.json", stem));
+    let json = serde_json::to_string_pretty(&report).with_context(|| "serializing report")?;
+    fs::write(&json_path, json).with_context(|| "writing JSON report")?;
+    fs::write(
+        reports_directory.join(format!("{}.txt", stem)),
+        report.to_text(),
+    )
+    .with_context(|| "writing text report")?;
+    Ok(json_path)
+}
+
+impl<'a> Report<'a> {
+    fn new(
+        started_at: DateTime<Local>,
+        options: &'a Options,
+        dates: &[NaiveDate],
+        state: &'a State,
+    ) -> Self {
+        let stages = [Stage::ResolveUrl, Stage::FetchImage, Stage::SaveImage]
+            .into_iter()
+            .map(|stage| {
+                let timing = state.stage_timing(stage);
+                let total_secs = timing.total.as_secs_f64();
+                StageReport {
+                    stage,
+                    count: timing.count,
+                    total_secs,
+                    average_secs: if timing.count > 0 {
+                        total_secs / timing.count as f64
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect();
+
+        let failed_dates = state.failed_dates();
+        let dates = dates
+            .iter()
+            .map(|&date| {
+                let warnings = state.date_warnings(date);
+                let status = if state.is_completed(date) {
+                    DateStatus::Completed
+                } else if failed_dates.contains(&date) {
+                    DateStatus::Failed
+                } else if warnings
+                    .iter()
+                    .any(|warning| matches!(warning, UpdateWarning::NotPublished { .. }))
+                {
+                    DateStatus::NotPublished
+                } else {
+                    DateStatus::Unfinished
+                };
+                DateReport {
+                    date,
+                    status,
+                    retries: warnings.iter().filter(|warning| warning.is_retry()).count(),
+                    warnings,
+                }
+            })
+            .collect();
+
+        Self {
+            version: VERSION,
+            started_at,
+            status: state.status(),
+            options,
+            duration_secs: state.elapsed().as_secs_f64(),
+            downloaded_bytes: state.downloaded_bytes(),
+            completed: state.completed_units(),
+            total: state.total_units(),
+            stages,
+            dates,
+            error: state.failure().map(|failure| ErrorReport {
+                date: failure.date,
+                chain: &failure.chain,
+            }),
+        }
+    }
+
+    /// Summary first, then only dates which did not complete cleanly.
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        // Writing to string cannot fail
+        let _ = self.write_text(&mut text);
+        text
+    }
+
+    fn write_text(&self, text: &mut String) -> std::fmt::Result {
+        let options = self.options;
+        writeln!(text, "everygarf run report")?;
+        writeln!(
+            text,
+            "started:    {}",
+            self.started_at.format("%Y-%m-%d %H:%M:%S")
+        )?;
+        writeln!(text, "status:     {:?}", self.status)?;
+        writeln!(text, "completed:  {}/{}", self.completed, self.total)?;
+        writeln!(
+            text,
+            "duration:   {}",
+            format_duration(std::time::Duration::from_secs_f64(self.duration_secs)),
+        )?;
+        writeln!(
+            text,
+            "downloaded: {}",
+            format_bytes(self.downloaded_bytes as f64)
+        )?;
+
+        writeln!(text, "\noptions")?;
+        writeln!(text, "  dates:    {} to {}", options.start, options.end)?;
+        if !options.weekdays.is_empty() {
+            let weekdays: Vec<_> = options.weekdays.iter().map(Weekday::to_string).collect();
+            writeln!(text, "  weekdays: {}", weekdays.join(", "))?;
+        }
+        if let Some(max_images) = options.max_images {
+            writeln!(text, "  max:      {}", max_images)?;
+        }
+        writeln!(text, "  jobs:     {}", options.jobs)?;
+        writeln!(text, "  attempts: {}", options.attempts)?;
+        writeln!(text, "  timeout:  {}s", options.timeout_secs)?;
+        writeln!(text, "  format:   {}", options.format)?;
+        for proxy in &options.proxies {
+            writeln!(text, "  proxy:    {}", proxy)?;
+        }
+        if let Some(cache) = &options.cache {
+            writeln!(text, "  cache:    {}", cache.display())?;
+        }
+        if options.skip_failed {
+            writeln!(text, "  skip failed dates")?;
+        }
+
+        writeln!(text, "\nstages")?;
+        for stage in &self.stages {
+            writeln!(
+                text,
+                "  {:<12} {:>6} dates, {:>8.2}s total, {:>6.2}s average",
+                format!("{:?}", stage.stage),
+                stage.count,
+                stage.total_secs,
+                stage.average_secs,
+            )?;
+        }
+
+        writeln!(text, "\ndates ({} attempted)", self.dates.len())?;
+        let mut is_clean = true;
+        for date in &self.dates {
+            if matches!(date.status, DateStatus::Completed) && date.warnings.is_empty() {
+                continue;
+            }
+            is_clean = false;
+            let status = match date.status {
+                DateStatus::Completed => "completed",
+                DateStatus::Failed => "failed",
+                DateStatus::NotPublished => "not published",
+                DateStatus::Unfinished => "unfinished",
+            };
+            writeln!(
+                text,
+                "  {} | {} ({} retries)",
+                date.date, status, date.retries
+            )?;
+            for warning in date.warnings {
+                writeln!(text, "    {}", describe_warning(*warning))?;
+            }
+        }
+        if is_clean {
+            writeln!(text, "  all completed without warnings")?;
+        }
+
+        if let Some(error) = &self.error {
+            writeln!(text, "\nerror")?;
+            if let Some(date) = error.date {
+                writeln!(text, "  date: {}", date)?;
+            }
+            for (depth, message) in error.chain.iter().enumerate() {
+                writeln!(text, "  {}{}", "  ".repeat(depth), message)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone as _;
+
+    use super::*;
+    use crate::state::{DateContext, Goal, Update, UpdateSuccess};
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2000, 1, day).unwrap()
+    }
+
+    fn options() -> Options {
+        Options {
+            start: date(1),
+            end: date(4),
+            weekdays: vec![Weekday::Sat, Weekday::Sun],
+            max_images: None,
+            jobs: 2,
+            attempts: 3,
+            timeout_secs: 10,
+            format: ImageFormat::Png,
+            proxies: Vec::new(),
+            cache: None,
+            skip_failed: false,
+        }
+    }
+
+    /// First date completed after a retry, second failed after a retry, third is not published
+    /// and fourth was still in progress when the run failed.
+    fn failed_state() -> State {
+        let mut state = State::new(4, Goal::Save);
+        state.update(Update::Success(UpdateSuccess::FetchCache {
+            generated: None,
+        }));
+        state.update(Update::Warning(UpdateWarning::FetchUrl {
+            attempt: 0,
+            date: date(1),
+        }));
+        state.update(Update::Success(UpdateSuccess::SaveImage { date: date(1) }));
+        state.update(Update::Warning(UpdateWarning::FetchImage {
+            attempt: 0,
+            date: date(2),
+        }));
+        state.update(Update::Warning(UpdateWarning::FetchImage {
+            attempt: 1,
+            date: date(2),
+        }));
+        state.update(Update::Warning(UpdateWarning::Failed { date: date(2) }));
+        state.update(Update::Warning(UpdateWarning::NotPublished {
+            date: date(3),
+        }));
+        state.update(Update::EnterStage {
+            date: date(4),
+            stage: Stage::FetchImage,
+        });
+        let error = anyhow::anyhow!("connection reset")
+            .context("failed to fetch image")
+            .context(DateContext(date(2)));
+        state.set_failed(&error);
+        state
+    }
+
+    fn report<'a>(options: &'a Options, state: &'a State) -> Report<'a> {
+        let started_at = Local.with_ymd_and_hms(2000, 1, 5, 12, 30, 0).unwrap();
+        let dates = [date(1), date(2), date(3), date(4)];
+        Report::new(started_at, options, &dates, state)
+    }
+
+    #[test]
+    fn date_status_and_retries() {
+        let options = options();
+        let state = failed_state();
+        let report = report(&options, &state);
+        let dates: Vec<_> = report
+            .dates
+            .iter()
+            .map(|date| (date.date, date.status, date.retries))
+            .collect();
+        assert_eq!(
+            dates,
+            [
+                (date(1), DateStatus::Completed, 1),
+                (date(2), DateStatus::Failed, 2),
+                (date(3), DateStatus::NotPublished, 0),
+                (date(4), DateStatus::Unfinished, 0),
+            ]
+        );
+        assert_eq!(report.completed, 1);
+        // Not published date is not counted
+        assert_eq!(report.total, 3);
+        let error = report.error.as_ref().unwrap();
+        assert_eq!(error.date, Some(date(2)));
+        assert_eq!(error.chain, ["failed to fetch image", "connection reset"]);
+    }
+
+    #[test]
+    fn json_report() {
+        let options = options();
+        let state = failed_state();
+        let json = serde_json::to_value(report(&options, &state)).unwrap();
+        assert_eq!(json["version"], VERSION);
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["options"]["format"], "png");
+        assert_eq!(
+            json["options"]["weekdays"],
+            serde_json::json!(["Sat", "Sun"])
+        );
+        assert_eq!(json["dates"][1]["status"], "failed");
+        assert_eq!(json["dates"][2]["status"], "not_published");
+        assert_eq!(json["dates"][3]["status"], "unfinished");
+        assert_eq!(json["dates"][1]["warnings"].as_array().unwrap().len(), 3);
+        assert_eq!(json["stages"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn text_report() {
+        let options = options();
+        let state = failed_state();
+        let text = report(&options, &state).to_text();
+        assert!(text.starts_with(
+            "everygarf run report\n\
+             started:    2000-01-05 12:30:00\n\
+             status:     Failed\n\
+             completed:  1/3\n"
+        ));
+        assert!(text.contains(
+            "options\n  \
+             dates:    2000-01-01 to 2000-01-04\n  \
+             weekdays: Sat, Sun\n  \
+             jobs:     2\n  \
+             attempts: 3\n  \
+             timeout:  10s\n  \
+             format:   png\n"
+        ));
+
+        let dates_start = text.find("dates (").unwrap();
+        let error_start = text.find("\nerror").unwrap();
+        assert_eq!(
+            &text[dates_start..error_start],
+            "dates (4 attempted)\n  \
+             2000-01-01 | completed (1 retries)\n    \
+             2000-01-01 | failed to fetch image url (attempt 1).\n  \
+             2000-01-02 | failed (2 retries)\n    \
+             2000-01-02 | failed to download image (attempt 1).\n    \
+             2000-01-02 | failed to download image (attempt 2).\n    \
+             2000-01-02 | failed.\n  \
+             2000-01-03 | not published (0 retries)\n    \
+             2000-01-03 | not published yet, skipped.\n  \
+             2000-01-04 | unfinished (0 retries)\n"
+        );
+        assert!(text.ends_with(
+            "error\n  \
+             date: 2000-01-02\n  \
+             failed to fetch image\n    \
+             connection reset\n"
+        ));
+    }
+
+    #[test]
+    fn clean_text_report() {
+        let options = options();
+        let mut state = State::new(1, Goal::Save);
+        state.update(Update::Success(UpdateSuccess::FetchCache {
+            generated: None,
+        }));
+        state.update(Update::Success(UpdateSuccess::SaveImage { date: date(1) }));
+        state.update(Update::Success(UpdateSuccess::Complete));
+        let started_at = Local.with_ymd_and_hms(2000, 1, 5, 12, 30, 0).unwrap();
+        let text = Report::new(started_at, &options, &[date(1)], &state).to_text();
+        assert!(text.ends_with("dates (1 attempted)\n  all completed without warnings\n"));
+    }
+}
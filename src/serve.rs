@@ -0,0 +1,571 @@
+//! Read-only HTTP server for the images in a target directory.
+//!
+//! Each comic is served as its image, or as JSON metadata by adding `.json` to the path.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use axum::Json;
+use axum::Router;
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::{StatusCode, header};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::routing::get;
+use chrono::NaiveDate;
+use everygarf::ImageFormat;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+
+use crate::download::get_source_url;
+use crate::io::get_target_directory;
+use crate::shutdown::Shutdown;
+
+/// Directory is scanned again after this long, to include newly saved images.
+const INDEX_TTL: Duration = Duration::from_secs(60);
+/// Most comics returned by a single range request. Larger ranges are rejected, rather than
+/// silently truncated.
+const MAX_RANGE: usize = 1000;
+
+struct Archive {
+    directory: PathBuf,
+    index: Mutex<Option<Index>>,
+}
+
+struct Index {
+    scanned: Instant,
+    images: Arc<BTreeMap<NaiveDate, ImageFormat>>,
+}
+
+#[derive(Serialize)]
+struct ComicMetadata {
+    date: NaiveDate,
+    format: ImageFormat,
+    /// Path of image on this server.
+    image: String,
+    size: u64,
+    source_url: String,
+    previous: Option<NaiveDate>,
+    next: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+struct RangeQuery {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+struct ViewQuery {
+    date: Option<NaiveDate>,
+    /// Any value selects a random comic.
+    random: Option<String>,
+}
+
+type SharedArchive = Arc<Archive>;
+
+pub fn run(directory: Option<PathBuf>, listen: SocketAddr) -> Result<()> {
+    let directory = match directory {
+        Some(directory) => directory,
+        None => get_target_directory()
+            .with_context(|| "failed to find appropriate target directory path")?,
+    };
+    if !directory.is_dir() {
+        anyhow::bail!("target directory does not exist: {}", directory.display());
+    }
+
+    let app = router(directory);
+
+    Runtime::new().unwrap().block_on(async move {
+        let shutdown = Shutdown::listen();
+        let listener = tokio::net::TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("failed to bind to {}", listen))?;
+        println!("listening on http://{}", listen);
+
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { shutdown.requested().await })
+            .await
+            .with_context(|| "server failed")
+    })
+}
+
+fn router(directory: PathBuf) -> Router {
+    let archive = Arc::new(Archive {
+        directory,
+        index: Mutex::new(None),
+    });
+    Router::new()
+        .route("/", get(handle_view))
+        .route("/comic/{date}", get(handle_comic))
+        .route("/latest", get(handle_latest))
+        .route("/latest.json", get(handle_latest_json))
+        .route("/random", get(handle_random))
+        .route("/random.json", get(handle_random_json))
+        .route("/range", get(handle_range))
+        .with_state(archive)
+}
+
+/// `/comic/2000-01-01` for image, `/comic/2000-01-01.json` for metadata.
+async fn handle_comic(
+    State(archive): State<SharedArchive>,
+    UrlPath(date): UrlPath<String>,
+) -> Response {
+    let (date, is_json) = match date.strip_suffix(".json") {
+        Some(date) => (date, true),
+        None => (date.as_str(), false),
+    };
+    let Ok(date) = date.parse::<NaiveDate>() else {
+        return error_response(StatusCode::BAD_REQUEST, "invalid date");
+    };
+    if is_json {
+        comic_metadata_response(&archive, Some(date)).await
+    } else {
+        comic_image_response(&archive, date).await
+    }
+}
+
+async fn handle_latest(State(archive): State<SharedArchive>) -> Response {
+    let latest = archive.images().await.keys().next_back().copied();
+    redirect_to_comic(latest)
+}
+
+async fn handle_latest_json(State(archive): State<SharedArchive>) -> Response {
+    let latest = archive.images().await.keys().next_back().copied();
+    comic_metadata_response(&archive, latest).await
+}
+
+async fn handle_random(State(archive): State<SharedArchive>) -> Response {
+    redirect_to_comic(archive.random_date().await)
+}
+
+async fn handle_random_json(State(archive): State<SharedArchive>) -> Response {
+    let date = archive.random_date().await;
+    comic_metadata_response(&archive, date).await
+}
+
+/// Metadata of all comics between dates (inclusive), oldest first.
+async fn handle_range(
+    State(archive): State<SharedArchive>,
+    Query(query): Query<RangeQuery>,
+) -> Response {
+    let from = query.from.unwrap_or(NaiveDate::MIN);
+    let to = query.to.unwrap_or(NaiveDate::MAX);
+    if from > to {
+        return error_response(StatusCode::BAD_REQUEST, "`from` must not be after `to`");
+    }
+    let images = archive.images().await;
+    if images.range(from..=to).nth(MAX_RANGE).is_some() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "too many comics in range; request a smaller range",
+        );
+    }
+    let archive = archive.clone();
+    let comics: Vec<_> = spawn_blocking(move || {
+        images
+            .range(from..=to)
+            .filter_map(|(&date, _)| archive.metadata(&images, date))
+            .collect()
+    })
+    .await;
+    Json(comics).into_response()
+}
+
+/// Browsable page for a single comic, with links to its neighbours.
+async fn handle_view(
+    State(archive): State<SharedArchive>,
+    Query(query): Query<ViewQuery>,
+) -> Response {
+    let images = archive.images().await;
+    let date = match query.random {
+        Some(_) => archive.random_date().await,
+        None => query.date.or_else(|| images.keys().next_back().copied()),
+    };
+    let Some(date) = date else {
+        return Html(render_page("everygarf", "<p>No comics saved yet.</p>")).into_response();
+    };
+    let metadata = {
+        let archive = archive.clone();
+        let images = images.clone();
+        spawn_blocking(move || archive.metadata(&images, date)).await
+    };
+    let Some(metadata) = metadata else {
+        let body = format!(
+            r#"<p>No comic saved for {}.</p><p><a href="/">Latest</a></p>"#,
+            date
+        );
+        return (StatusCode::NOT_FOUND, Html(render_page("everygarf", &body))).into_response();
+    };
+
+    let link = |label: &str, date: Option<NaiveDate>| match date {
+        Some(date) => format!(r#"<a href="/?date={}">{}</a>"#, date, label),
+        None => format!("<span>{}</span>", label),
+    };
+    let mut body = String::new();
+    let _ = write!(
+        body,
+        concat!(
+            r#"<nav>{} {} <a href="/?random">Random</a> {} {}</nav>"#,
+            r#"<form><input type="date" name="date" value="{}"> <button>Go</button></form>"#,
+            r#"<p><a href="{}"><img src="{}" alt="Garfield comic for {}"></a></p>"#,
+            r#"<p><a href="{}">{}</a> | <a href="/comic/{}.json">JSON</a></p>"#,
+        ),
+        link("First", images.keys().next().copied()),
+        link("Previous", metadata.previous),
+        link("Next", metadata.next),
+        link("Latest", images.keys().next_back().copied()),
+        date,
+        metadata.source_url,
+        metadata.image,
+        date,
+        metadata.source_url,
+        date.format("%A, %-d %B %Y"),
+        date,
+    );
+    Html(render_page(&format!("everygarf | {}", date), &body)).into_response()
+}
+
+impl Archive {
+    /// Scanned again if stale. Other requests wait for the scan, rather than scanning too.
+    async fn images(&self) -> Arc<BTreeMap<NaiveDate, ImageFormat>> {
+        let mut index = self.index.lock().await;
+        if let Some(index) = index.as_ref()
+            && index.scanned.elapsed() < INDEX_TTL
+        {
+            return index.images.clone();
+        }
+        let directory = self.directory.clone();
+        let images = Arc::new(spawn_blocking(move || scan_images(&directory)).await);
+        *index = Some(Index {
+            scanned: Instant::now(),
+            images: images.clone(),
+        });
+        images
+    }
+
+    async fn random_date(&self) -> Option<NaiveDate> {
+        let images = self.images().await;
+        if images.is_empty() {
+            return None;
+        }
+        images.keys().nth(fastrand::usize(..images.len())).copied()
+    }
+
+    fn image_path(&self, date: NaiveDate, format: ImageFormat) -> PathBuf {
+        self.directory
+            .join(format!("{}.{}", date.format("%Y-%m-%d"), format))
+    }
+
+    /// Blocking, as it reads size of image.
+    fn metadata(
+        &self,
+        images: &BTreeMap<NaiveDate, ImageFormat>,
+        date: NaiveDate,
+    ) -> Option<ComicMetadata> {
+        let format = *images.get(&date)?;
+        // Image may have been removed since scan
+        let size = fs::metadata(self.image_path(date, format)).ok()?.len();
+        Some(ComicMetadata {
+            date,
+            format,
+            image: format!("/comic/{}", date),
+            size,
+            source_url: get_source_url(date),
+            previous: images.range(..date).next_back().map(|(&date, _)| date),
+            next: images.range(date..).nth(1).map(|(&date, _)| date),
+        })
+    }
+}
+
+async fn comic_image_response(archive: &Archive, date: NaiveDate) -> Response {
+    // Checked directly, so new images are served before the next scan
+    for format in [ImageFormat::Gif, ImageFormat::Png] {
+        let path = archive.image_path(date, format);
+        let Ok(bytes) = spawn_blocking(move || fs::read(path)).await else {
+            continue;
+        };
+        let content_type = match format {
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Png => "image/png",
+        };
+        return (
+            [
+                (header::CONTENT_TYPE, content_type),
+                // Saved images do not change
+                (header::CACHE_CONTROL, "public, max-age=86400"),
+            ],
+            bytes,
+        )
+            .into_response();
+    }
+    error_response(StatusCode::NOT_FOUND, "no comic saved for date")
+}
+
+async fn comic_metadata_response(archive: &SharedArchive, date: Option<NaiveDate>) -> Response {
+    let images = archive.images().await;
+    let archive = archive.clone();
+    let metadata = spawn_blocking(move || date.and_then(|date| archive.metadata(&images, date)));
+    match metadata.await {
+        Some(metadata) => Json(metadata).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, "no comic saved for date"),
+    }
+}
+
+fn redirect_to_comic(date: Option<NaiveDate>) -> Response {
+    match date {
+        Some(date) => Redirect::temporary(&format!("/comic/{}", date)).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, "no comics saved"),
+    }
+}
+
+fn error_response(status: StatusCode, message: &'static str) -> Response {
+    (status, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+/// Filesystem calls must not block the runtime threads which serve requests.
+async fn spawn_blocking<T: Send + 'static>(func: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(func)
+        .await
+        .expect("Blocking task should not panic")
+}
+
+fn scan_images(directory: &Path) -> BTreeMap<NaiveDate, ImageFormat> {
+    let mut images = BTreeMap::new();
+    let Ok(children) = fs::read_dir(directory) else {
+        return images;
+    };
+    for child in children.flatten() {
+        let path = child.path();
+        let format = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gif") => ImageFormat::Gif,
+            Some("png") => ImageFormat::Png,
+            _ => continue,
+        };
+        let Some(Ok(date)) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map(|stem| NaiveDate::parse_from_str(stem, "%Y-%m-%d"))
+        else {
+            continue;
+        };
+        images.insert(date, format);
+    }
+    images
+}
+
+fn render_page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 60rem; margin: 1rem auto; padding: 0 1rem; text-align: center; }}
+nav a, nav span {{ margin: 0 0.5rem; }}
+nav span {{ color: #999; }}
+form {{ margin: 1rem 0; }}
+img {{ max-width: 100%; }}
+</style>
+</head>
+<body>
+{}
+</body>
+</html>
+"#,
+        title, body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Target directory, unique to the test, containing an empty image for each date.
+    fn temp_directory(name: &str, images: &[(&str, &str)]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "everygarf-test-serve-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        for (date, extension) in images {
+            fs::write(path.join(format!("{}.{}", date, extension)), date).unwrap();
+        }
+        path
+    }
+
+    /// Serves directory on a free port, returning base URL.
+    async fn start_server(directory: PathBuf) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(directory)).await });
+        format!("http://{}", address)
+    }
+
+    async fn get(base: &str, path: &str) -> reqwest::Response {
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        client
+            .get(format!("{}{}", base, path))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn get_json(base: &str, path: &str) -> (StatusCode, serde_json::Value) {
+        let response = get(base, path).await;
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap();
+        (status, response.json().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn comic_image_and_metadata() {
+        let directory = temp_directory(
+            "comic",
+            &[
+                ("2000-01-01", "gif"),
+                ("2000-01-02", "png"),
+                ("2000-01-05", "gif"),
+            ],
+        );
+        fs::write(directory.join("notes.txt"), "").unwrap();
+        let base = start_server(directory).await;
+
+        let response = get(&base, "/comic/2000-01-02").await;
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["content-type"], "image/png");
+        assert_eq!(response.text().await.unwrap(), "2000-01-02");
+
+        let (status, metadata) = get_json(&base, "/comic/2000-01-02.json").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(metadata["date"], "2000-01-02");
+        assert_eq!(metadata["format"], "png");
+        assert_eq!(metadata["image"], "/comic/2000-01-02");
+        assert_eq!(metadata["size"], 10);
+        assert_eq!(metadata["previous"], "2000-01-01");
+        assert_eq!(metadata["next"], "2000-01-05");
+
+        let (status, metadata) = get_json(&base, "/comic/2000-01-01.json").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(metadata["previous"].is_null());
+
+        let (status, error) = get_json(&base, "/comic/2000-01-03").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error["error"], "no comic saved for date");
+        let (status, _) = get_json(&base, "/comic/2000-01-03.json").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get_json(&base, "/comic/yesterday").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn latest_and_random() {
+        let directory = temp_directory("latest", &[("2000-01-01", "gif"), ("2000-01-05", "png")]);
+        let base = start_server(directory).await;
+
+        let response = get(&base, "/latest").await;
+        assert_eq!(response.status().as_u16(), 307);
+        assert_eq!(response.headers()["location"], "/comic/2000-01-05");
+
+        let (status, metadata) = get_json(&base, "/latest.json").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(metadata["date"], "2000-01-05");
+        assert!(metadata["next"].is_null());
+
+        let response = get(&base, "/random").await;
+        assert_eq!(response.status().as_u16(), 307);
+        let location = response.headers()["location"].to_str().unwrap();
+        assert!(["/comic/2000-01-01", "/comic/2000-01-05"].contains(&location));
+
+        let (status, _) = get_json(&base, "/random.json").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn empty_directory() {
+        let base = start_server(temp_directory("empty", &[])).await;
+        let (status, error) = get_json(&base, "/latest").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error["error"], "no comics saved");
+        let (status, _) = get_json(&base, "/random.json").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let page = get(&base, "/").await.text().await.unwrap();
+        assert!(page.contains("No comics saved yet."));
+    }
+
+    #[tokio::test]
+    async fn range() {
+        let directory = temp_directory(
+            "range",
+            &[
+                ("2000-01-01", "gif"),
+                ("2000-01-02", "png"),
+                ("2000-01-05", "gif"),
+            ],
+        );
+        let base = start_server(directory).await;
+
+        let (status, comics) = get_json(&base, "/range?from=2000-01-02").await;
+        assert_eq!(status, StatusCode::OK);
+        let dates: Vec<_> = comics
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|comic| comic["date"].as_str().unwrap())
+            .collect();
+        assert_eq!(dates, ["2000-01-02", "2000-01-05"]);
+
+        let (status, comics) = get_json(&base, "/range").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(comics.as_array().unwrap().len(), 3);
+
+        let (status, _) = get_json(&base, "/range?from=2000-01-05&to=2000-01-01").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn range_too_large() {
+        let start = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+        let dates: Vec<_> = start
+            .iter_days()
+            .take(MAX_RANGE + 1)
+            .map(|date| date.to_string())
+            .collect();
+        let images: Vec<_> = dates.iter().map(|date| (date.as_str(), "gif")).collect();
+        let base = start_server(temp_directory("range-large", &images)).await;
+
+        let (status, error) = get_json(&base, "/range").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            error["error"],
+            "too many comics in range; request a smaller range"
+        );
+
+        let path = format!("/range?to={}", dates[MAX_RANGE - 1]);
+        let (status, comics) = get_json(&base, &path).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(comics.as_array().unwrap().len(), MAX_RANGE);
+    }
+
+    #[tokio::test]
+    async fn view_page() {
+        let directory = temp_directory("view", &[("2000-01-01", "gif"), ("2000-01-05", "png")]);
+        let base = start_server(directory).await;
+        let page = get(&base, "/").await.text().await.unwrap();
+        assert!(page.contains(r#"src="/comic/2000-01-05""#));
+        let page = get(&base, "/?date=2000-01-01").await.text().await.unwrap();
+        assert!(page.contains(r#"src="/comic/2000-01-01""#));
+    }
+}
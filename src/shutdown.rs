@@ -0,0 +1,58 @@
+use tokio::signal;
+use tokio::sync::watch;
+
+/// Exit code for a run aborted by a second signal, as if killed by `SIGINT`.
+const ABORT_EXIT_CODE: i32 = 130;
+
+/// Request to stop, from the first Ctrl-C (or `SIGTERM`).
+///
+/// No new dates should be started once requested, but in-flight dates may finish.
+/// A second signal exits the process immediately.
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Start listening for signals. Must be called inside runtime.
+    pub fn listen() -> Self {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            tx.send_replace(true);
+            wait_for_signal().await;
+            eprintln!("aborted.");
+            std::process::exit(ABORT_EXIT_CODE);
+        });
+        Self { rx }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Wait until shutdown is requested.
+    pub async fn requested(&self) {
+        let mut rx = self.rx.clone();
+        if rx.wait_for(|requested| *requested).await.is_err() {
+            // Listener has stopped, so shutdown will never be requested
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+    }
+}
@@ -1,95 +1,331 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
 use chrono::NaiveDate;
+use serde::Serialize;
 
-#[derive(Clone, Copy, Debug)]
+/// Warnings kept for display.
+pub const RECENT_WARNING_COUNT: usize = 3;
+
+#[derive(Clone, Debug)]
 pub struct State {
+    goal: Goal,
     status: Status,
     is_first_draw: bool,
+    started: Instant,
+    working_since: Option<Instant>,
 
     latest_success: Option<UpdateSuccess>,
-    latest_warning: Option<UpdateWarning>,
+    recent_warnings: VecDeque<UpdateWarning>,
+    warning_count: usize,
+
+    /// Current stage of each unfinished date, and when it was entered.
+    active_jobs: HashMap<NaiveDate, (Stage, Instant)>,
+    stage_timings: HashMap<Stage, StageTiming>,
+    completed_dates: HashSet<NaiveDate>,
+    failed_dates: Vec<NaiveDate>,
+    /// All warnings which refer to a date, oldest first.
+    date_warnings: HashMap<NaiveDate, Vec<UpdateWarning>>,
+    failure: Option<Failure>,
+    downloaded_bytes: u64,
 
     completed_units: usize,
     total_units: usize,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Status {
     // Prologue
     PingProxy,
     FetchCache,
     // Main download
     Working,
+    /// Shutdown requested; in-flight dates are finishing.
+    Stopping,
     // Epilogue
     Complete,
+    Interrupted,
     Failed,
 }
 
+/// Which stage marks a date as completed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Goal {
+    /// Only resolve image url.
+    Resolve,
+    /// Download and save image.
+    Save,
+}
+
+/// Stage of a single date.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    ResolveUrl,
+    FetchImage,
+    SaveImage,
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum Update {
+    /// Date has moved to a new stage; not reported on its own.
+    EnterStage {
+        date: NaiveDate,
+        stage: Stage,
+    },
     Success(UpdateSuccess),
     Warning(UpdateWarning),
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
 pub enum UpdateSuccess {
     // Prologue
     ProxyPing,
-    FetchCache,
+    FetchCache { generated: Option<NaiveDate> },
     // Main download
     FetchUrl { date: NaiveDate },
-    FetchImage { date: NaiveDate },
+    FetchImage { date: NaiveDate, bytes: u64 },
     SaveImage { date: NaiveDate },
     // Epilogue
     Complete,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
 pub enum UpdateWarning {
-    FetchUrl { attempt: usize, date: NaiveDate },
-    FetchImage { attempt: usize, date: NaiveDate },
+    FetchUrl {
+        #[serde(serialize_with = "serialize_attempt")]
+        attempt: usize,
+        date: NaiveDate,
+    },
+    FetchImage {
+        #[serde(serialize_with = "serialize_attempt")]
+        attempt: usize,
+        date: NaiveDate,
+    },
+    DisableProxy {
+        index: usize,
+    },
+    /// Date could not be completed; followed by the error.
+    Failed {
+        date: NaiveDate,
+    },
+    /// Image is still saved.
+    HookFailed {
+        date: NaiveDate,
+    },
+    /// Date is skipped, without failing the run.
+    NotPublished {
+        date: NaiveDate,
+    },
+}
+
+/// Time spent by all dates in a stage.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StageTiming {
+    /// Number of times the stage was left, whether or not it succeeded.
+    pub count: usize,
+    pub total: Duration,
+}
+
+/// Error which stopped the run.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub date: Option<NaiveDate>,
+    /// Outermost context first.
+    pub chain: Vec<String>,
+}
+
+/// Context for an error of a single date, so it can be attributed in reports.
+#[derive(Debug)]
+pub struct DateContext(pub NaiveDate);
+
+impl fmt::Display for DateContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Attempts are counted from 1 in any output.
+fn serialize_attempt<S: serde::Serializer>(
+    attempt: &usize,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(*attempt as u64 + 1)
+}
+
+impl UpdateWarning {
+    pub fn date(&self) -> Option<NaiveDate> {
+        match *self {
+            Self::FetchUrl { date, .. }
+            | Self::FetchImage { date, .. }
+            | Self::Failed { date }
+            | Self::HookFailed { date }
+            | Self::NotPublished { date } => Some(date),
+            Self::DisableProxy { .. } => None,
+        }
+    }
+
+    /// Warning is for a failed attempt, which is followed by another.
+    pub fn is_retry(&self) -> bool {
+        matches!(self, Self::FetchUrl { .. } | Self::FetchImage { .. })
+    }
 }
 
 impl State {
-    pub fn new(total_units: usize) -> Self {
+    pub fn new(total_units: usize, goal: Goal) -> Self {
         Self {
+            goal,
             status: Status::PingProxy,
             is_first_draw: true,
+            started: Instant::now(),
+            working_since: None,
 
             latest_success: None,
-            latest_warning: None,
+            recent_warnings: VecDeque::with_capacity(RECENT_WARNING_COUNT),
+            warning_count: 0,
+
+            active_jobs: HashMap::new(),
+            stage_timings: HashMap::new(),
+            completed_dates: HashSet::new(),
+            failed_dates: Vec::new(),
+            date_warnings: HashMap::new(),
+            failure: None,
+            downloaded_bytes: 0,
 
             completed_units: 0,
             total_units,
         }
     }
 
-    pub fn set_failed(&mut self) {
+    pub fn set_failed(&mut self, error: &anyhow::Error) {
         self.status = Status::Failed;
         self.latest_success = None;
+        let date = error.downcast_ref::<DateContext>().map(|context| context.0);
+        // Date is already recorded separately
+        let skip = usize::from(date.is_some());
+        self.failure = Some(Failure {
+            date,
+            chain: error.chain().skip(skip).map(ToString::to_string).collect(),
+        });
+    }
+
+    pub fn set_stopping(&mut self) {
+        self.status = Status::Stopping;
+    }
+
+    pub fn set_interrupted(&mut self) {
+        self.status = Status::Interrupted;
+        self.end_all_jobs();
     }
 
     pub fn update(&mut self, update: Update) {
         match update {
+            Update::EnterStage { date, stage } => {
+                self.start_working();
+                self.end_job(date);
+                self.active_jobs.insert(date, (stage, Instant::now()));
+            }
             Update::Success(success) => {
                 self.latest_success = Some(success);
                 match success {
-                    UpdateSuccess::ProxyPing => self.status = Status::FetchCache,
-                    UpdateSuccess::FetchCache => self.status = Status::Working,
-                    UpdateSuccess::Complete => self.status = Status::Complete,
+                    // Stopping during prologue must not be forgotten
+                    UpdateSuccess::ProxyPing if self.status == Status::PingProxy => {
+                        self.status = Status::FetchCache;
+                    }
+                    UpdateSuccess::ProxyPing => (),
+                    UpdateSuccess::FetchCache { .. } => self.start_working(),
+                    UpdateSuccess::Complete => {
+                        self.status = Status::Complete;
+                        self.end_all_jobs();
+                    }
 
-                    UpdateSuccess::SaveImage { .. } => {
-                        self.increase_complete_units();
+                    UpdateSuccess::FetchUrl { date } => {
+                        self.start_working();
+                        if self.goal == Goal::Resolve {
+                            self.complete_unit(date);
+                        }
+                    }
+                    UpdateSuccess::FetchImage { bytes, .. } => {
+                        self.start_working();
+                        self.downloaded_bytes += bytes;
+                    }
+                    UpdateSuccess::SaveImage { date } => {
+                        self.start_working();
+                        if self.goal == Goal::Save {
+                            self.complete_unit(date);
+                        }
                     }
-                    _ => (),
                 }
             }
             Update::Warning(warning) => {
-                self.latest_warning = Some(warning);
+                match warning {
+                    UpdateWarning::NotPublished { date } => self.skip_unit(date),
+                    UpdateWarning::Failed { date } => {
+                        self.end_job(date);
+                        self.failed_dates.push(date);
+                    }
+                    _ => (),
+                }
+                if let Some(date) = warning.date() {
+                    self.date_warnings.entry(date).or_default().push(warning);
+                }
+                if self.recent_warnings.len() >= RECENT_WARNING_COUNT {
+                    self.recent_warnings.pop_front();
+                }
+                self.recent_warnings.push_back(warning);
+                self.warning_count += 1;
             }
         }
     }
 
-    fn increase_complete_units(&mut self) {
-        if self.status != Status::Working {
+    /// Prologue stages may be skipped (no proxy or no cache).
+    fn start_working(&mut self) {
+        if matches!(self.status, Status::PingProxy | Status::FetchCache) {
+            self.status = Status::Working;
+            self.working_since = Some(Instant::now());
+        }
+    }
+
+    /// Add units after starting, such as retried dates.
+    pub fn add_units(&mut self, count: usize) {
+        self.total_units += count;
+    }
+
+    /// Forget a date which failed without stopping the run.
+    pub fn remove_job(&mut self, date: NaiveDate) {
+        self.end_job(date);
+    }
+
+    /// Record time spent in current stage of date, if any.
+    fn end_job(&mut self, date: NaiveDate) {
+        if let Some((stage, since)) = self.active_jobs.remove(&date) {
+            let timing = self.stage_timings.entry(stage).or_default();
+            timing.count += 1;
+            timing.total += since.elapsed();
+        }
+    }
+
+    fn end_all_jobs(&mut self) {
+        let dates: Vec<_> = self.active_jobs.keys().copied().collect();
+        for date in dates {
+            self.end_job(date);
+        }
+    }
+
+    /// Date will never be completed in this run.
+    fn skip_unit(&mut self, date: NaiveDate) {
+        self.end_job(date);
+        self.total_units = self.total_units.saturating_sub(1);
+    }
+
+    fn complete_unit(&mut self, date: NaiveDate) {
+        self.end_job(date);
+        self.completed_dates.insert(date);
+        if !matches!(self.status, Status::Working | Status::Stopping) {
             return;
         }
         if self.completed_units < self.total_units {
@@ -105,8 +341,13 @@ impl State {
         self.latest_success
     }
 
-    pub fn latest_warning(&self) -> Option<UpdateWarning> {
-        self.latest_warning
+    /// Oldest first.
+    pub fn recent_warnings(&self) -> impl Iterator<Item = UpdateWarning> {
+        self.recent_warnings.iter().copied()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.warning_count
     }
 
     pub fn completed_units(&self) -> usize {
@@ -117,9 +358,166 @@ impl State {
         self.total_units
     }
 
+    pub fn goal(&self) -> Goal {
+        self.goal
+    }
+
+    /// Number of unfinished dates in this stage.
+    pub fn stage_count(&self, stage: Stage) -> usize {
+        self.active_jobs
+            .values()
+            .filter(|&&(current, _)| current == stage)
+            .count()
+    }
+
+    pub fn failed_dates(&self) -> &[NaiveDate] {
+        &self.failed_dates
+    }
+
+    pub fn is_completed(&self, date: NaiveDate) -> bool {
+        self.completed_dates.contains(&date)
+    }
+
+    /// Empty if date had no warnings.
+    pub fn date_warnings(&self, date: NaiveDate) -> &[UpdateWarning] {
+        self.date_warnings
+            .get(&date)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn stage_timing(&self, stage: Stage) -> StageTiming {
+        self.stage_timings.get(&stage).copied().unwrap_or_default()
+    }
+
+    pub fn failure(&self) -> Option<&Failure> {
+        self.failure.as_ref()
+    }
+
+    /// Time since run started, including prologue.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes
+    }
+
+    /// Time since main download started, excluding prologue.
+    pub fn working_elapsed(&self) -> Duration {
+        self.working_since
+            .map(|since| since.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Completed units per second.
+    pub fn unit_rate(&self) -> f64 {
+        rate(self.completed_units as f64, self.working_elapsed())
+    }
+
+    /// Downloaded bytes per second.
+    pub fn byte_rate(&self) -> f64 {
+        rate(self.downloaded_bytes as f64, self.working_elapsed())
+    }
+
+    /// Estimated time until all units are completed, at current rate.
+    pub fn eta(&self) -> Option<Duration> {
+        let unit_rate = self.unit_rate();
+        if unit_rate <= 0.0 {
+            return None;
+        }
+        let remaining = self.total_units.saturating_sub(self.completed_units);
+        Some(Duration::from_secs_f64(remaining as f64 / unit_rate))
+    }
+
+    pub fn percent(&self) -> f32 {
+        if self.total_units == 0 {
+            return 100.0;
+        }
+        self.completed_units as f32 * 100.0 / self.total_units as f32
+    }
+
+    /// Next draw should not clear previous lines.
+    pub fn reset_draw(&mut self) {
+        self.is_first_draw = true;
+    }
+
     pub fn record_draw(&mut self) -> bool {
         let was_first_draw = self.is_first_draw;
         self.is_first_draw = false;
         was_first_draw
     }
 }
+
+fn rate(amount: f64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs <= 0.0 {
+        return 0.0;
+    }
+    amount / secs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2000, 1, day).unwrap()
+    }
+
+    #[test]
+    fn prologue_then_working() {
+        let mut state = State::new(1, Goal::Save);
+        assert_eq!(state.status(), Status::PingProxy);
+        state.update(Update::Success(UpdateSuccess::ProxyPing));
+        assert_eq!(state.status(), Status::FetchCache);
+        state.update(Update::Success(UpdateSuccess::FetchCache {
+            generated: None,
+        }));
+        assert_eq!(state.status(), Status::Working);
+    }
+
+    #[test]
+    fn stopping_during_prologue_is_kept() {
+        let mut state = State::new(1, Goal::Save);
+        state.update(Update::Success(UpdateSuccess::ProxyPing));
+        state.set_stopping();
+        state.update(Update::Success(UpdateSuccess::FetchCache {
+            generated: None,
+        }));
+        assert_eq!(state.status(), Status::Stopping);
+
+        let mut state = State::new(1, Goal::Save);
+        state.set_stopping();
+        state.update(Update::Success(UpdateSuccess::ProxyPing));
+        assert_eq!(state.status(), Status::Stopping);
+        state.update(Update::EnterStage {
+            date: date(1),
+            stage: Stage::ResolveUrl,
+        });
+        assert_eq!(state.status(), Status::Stopping);
+    }
+
+    #[test]
+    fn interrupted_is_kept() {
+        let mut state = State::new(1, Goal::Save);
+        state.set_interrupted();
+        state.update(Update::Success(UpdateSuccess::ProxyPing));
+        state.update(Update::Success(UpdateSuccess::FetchCache {
+            generated: None,
+        }));
+        assert_eq!(state.status(), Status::Interrupted);
+    }
+
+    #[test]
+    fn in_flight_dates_complete_while_stopping() {
+        let mut state = State::new(2, Goal::Save);
+        state.update(Update::Success(UpdateSuccess::FetchCache {
+            generated: None,
+        }));
+        state.set_stopping();
+        state.update(Update::Success(UpdateSuccess::SaveImage { date: date(1) }));
+        assert_eq!(state.status(), Status::Stopping);
+        assert_eq!(state.completed_units(), 1);
+    }
+}
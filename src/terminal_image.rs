@@ -0,0 +1,266 @@
+//! Draw an image inline in the terminal, scaled to fit the terminal size.
+//!
+//! Graphics protocols are detected from environment variables, as these are forwarded over SSH
+//! (`TERM` at least), unlike a terminal query.
+
+use std::io::{self, Write as _};
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use clap::ValueEnum;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView as _, RgbImage};
+
+/// Assumed size of a terminal cell in pixels, as it cannot be found reliably.
+const CELL_WIDTH: u32 = 10;
+const CELL_HEIGHT: u32 = 20;
+/// Rows left for the prompt after drawing.
+const MARGIN_ROWS: u16 = 2;
+/// Largest chunk of a kitty graphics escape sequence.
+const KITTY_CHUNK_SIZE: usize = 4096;
+/// Levels of each channel in the sixel palette.
+const SIXEL_LEVELS: u32 = 6;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Protocol {
+    /// Detect from terminal, falling back to `blocks`
+    #[default]
+    Auto,
+    /// Kitty graphics protocol (kitty, Ghostty, WezTerm)
+    Kitty,
+    /// iTerm2 inline images (iTerm2, WezTerm)
+    Iterm,
+    /// Sixel graphics (foot, mlterm, xterm with sixel enabled)
+    Sixel,
+    /// Unicode half blocks with 24-bit color, supported by most terminals
+    Blocks,
+}
+
+/// Size of area to draw in, in terminal cells.
+#[derive(Clone, Copy, Debug)]
+struct Cells {
+    columns: u32,
+    rows: u32,
+}
+
+pub fn draw(path: &Path, protocol: Protocol) -> Result<()> {
+    let bytes = std::fs::read(path).with_context(|| "reading image file")?;
+    let image = image::load_from_memory(&bytes).with_context(|| "decoding image")?;
+    let cells = fit_to_terminal(&image);
+
+    let output = match protocol.resolve() {
+        Protocol::Kitty => encode_kitty(&image, cells)?,
+        // Terminal decodes original file
+        Protocol::Iterm => encode_iterm(&bytes, cells),
+        Protocol::Sixel => encode_sixel(&image, cells),
+        Protocol::Blocks | Protocol::Auto => encode_blocks(&image, cells),
+    };
+    let mut stdout = io::stdout().lock();
+    stdout
+        .write_all(&output)
+        .and_then(|()| stdout.flush())
+        .with_context(|| "writing image to terminal")
+}
+
+impl Protocol {
+    fn resolve(self) -> Self {
+        if self != Self::Auto {
+            return self;
+        }
+        let var = |name| std::env::var(name).unwrap_or_default();
+        let term = var("TERM");
+        let term_program = var("TERM_PROGRAM");
+
+        if term == "xterm-kitty"
+            || term == "xterm-ghostty"
+            || std::env::var_os("KITTY_WINDOW_ID").is_some()
+            || term_program == "ghostty"
+            || term_program == "WezTerm"
+        {
+            Self::Kitty
+        } else if term_program == "iTerm.app" || var("LC_TERMINAL") == "iTerm2" {
+            Self::Iterm
+        } else if term.starts_with("foot") || term.starts_with("mlterm") || term.contains("sixel") {
+            Self::Sixel
+        } else {
+            Self::Blocks
+        }
+    }
+}
+
+/// Largest area with the aspect ratio of the image, which fits in the terminal.
+fn fit_to_terminal(image: &DynamicImage) -> Cells {
+    let (columns, rows) = terminal_size::terminal_size()
+        .map(|(width, height)| (width.0, height.0))
+        .unwrap_or((80, 24));
+    let max_columns = u32::from(columns.max(1));
+    let max_rows = u32::from(rows.saturating_sub(MARGIN_ROWS).max(1));
+
+    let (width, height) = image.dimensions();
+    let width = u64::from(width.max(1));
+    let height = u64::from(height.max(1));
+    // Rows needed for full width, with cells taller than they are wide
+    let rows_for_width = (u64::from(max_columns * CELL_WIDTH) * height)
+        .div_ceil(width * u64::from(CELL_HEIGHT)) as u32;
+    if rows_for_width <= max_rows {
+        return Cells {
+            columns: max_columns,
+            rows: rows_for_width.max(1),
+        };
+    }
+    let columns =
+        (u64::from(max_rows * CELL_HEIGHT) * width / (height * u64::from(CELL_WIDTH))) as u32;
+    Cells {
+        columns: columns.max(1),
+        rows: max_rows,
+    }
+}
+
+/// Terminal scales image to the given columns, keeping aspect ratio.
+fn encode_kitty(image: &DynamicImage, cells: Cells) -> Result<Vec<u8>> {
+    let mut png = Vec::new();
+    image
+        .write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .with_context(|| "encoding image as PNG")?;
+    let data = BASE64.encode(&png);
+
+    let mut output = Vec::new();
+    let chunks: Vec<_> = data.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(index + 1 < chunks.len());
+        if index == 0 {
+            // Quiet mode, as responses would be printed as input
+            write!(
+                output,
+                "\x1b_Gf=100,a=T,q=2,c={},m={};",
+                cells.columns, more
+            )?;
+        } else {
+            write!(output, "\x1b_Gm={};", more)?;
+        }
+        output.extend_from_slice(chunk);
+        output.extend_from_slice(b"\x1b\\");
+    }
+    output.push(b'\n');
+    Ok(output)
+}
+
+fn encode_iterm(bytes: &[u8], cells: Cells) -> Vec<u8> {
+    let mut output = format!(
+        "\x1b]1337;File=inline=1;size={};width={};preserveAspectRatio=1:",
+        bytes.len(),
+        cells.columns,
+    )
+    .into_bytes();
+    output.extend_from_slice(BASE64.encode(bytes).as_bytes());
+    output.extend_from_slice(b"\x07\n");
+    output
+}
+
+/// Image is quantized to a fixed palette, which suits the flat colors of comics.
+fn encode_sixel(image: &DynamicImage, cells: Cells) -> Vec<u8> {
+    let image = resize(image, cells.columns * CELL_WIDTH, cells.rows * CELL_HEIGHT);
+    let (width, height) = image.dimensions();
+    let indices: Vec<u32> = image.pixels().map(|pixel| palette_index(pixel.0)).collect();
+
+    let mut output = String::new();
+    // Pixel aspect ratio 1:1, and width and height given upfront
+    output += &format!("\x1bP0;1;0q\"1;1;{};{}", width, height);
+    let max = SIXEL_LEVELS - 1;
+    for index in 0..SIXEL_LEVELS.pow(3) {
+        let [red, green, blue] = [
+            index / SIXEL_LEVELS / SIXEL_LEVELS,
+            index / SIXEL_LEVELS % SIXEL_LEVELS,
+            index % SIXEL_LEVELS,
+        ]
+        .map(|level| level * 100 / max);
+        output += &format!("#{};2;{};{};{}", index, red, green, blue);
+    }
+
+    for band in 0..height.div_ceil(6) {
+        let top = band * 6;
+        let rows = (height - top).min(6);
+        let mut colors: Vec<u32> = (0..rows)
+            .flat_map(|row| {
+                let start = ((top + row) * width) as usize;
+                indices[start..start + width as usize].iter().copied()
+            })
+            .collect();
+        colors.sort_unstable();
+        colors.dedup();
+
+        for (color_index, &color) in colors.iter().enumerate() {
+            if color_index > 0 {
+                // Return to start of band, to overlay next color
+                output.push('$');
+            }
+            output += &format!("#{}", color);
+            let sixels = (0..width).map(|x| {
+                let bits = (0..rows)
+                    .filter(|&row| indices[((top + row) * width + x) as usize] == color)
+                    .fold(0u8, |bits, row| bits | 1 << row);
+                char::from(0x3f + bits)
+            });
+            push_run_length(&mut output, sixels);
+        }
+        output.push('-');
+    }
+    output += "\x1b\\\n";
+    output.into_bytes()
+}
+
+/// Each cell shows two pixels, as foreground (top) and background (bottom) colors.
+fn encode_blocks(image: &DynamicImage, cells: Cells) -> Vec<u8> {
+    let image = resize(image, cells.columns, cells.rows * 2);
+    let (width, height) = image.dimensions();
+
+    let mut output = String::new();
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let [r1, g1, b1] = image.get_pixel(x, y).0;
+            let [r2, g2, b2] = if y + 1 < height {
+                image.get_pixel(x, y + 1).0
+            } else {
+                [0, 0, 0]
+            };
+            output += &format!(
+                "\x1b[38;2;{};{};{};48;2;{};{};{}m\u{2580}",
+                r1, g1, b1, r2, g2, b2
+            );
+        }
+        output += "\x1b[0m\n";
+    }
+    output.into_bytes()
+}
+
+/// Fit within size, keeping aspect ratio.
+fn resize(image: &DynamicImage, width: u32, height: u32) -> RgbImage {
+    image
+        .resize(width.max(1), height.max(1), FilterType::Triangle)
+        .to_rgb8()
+}
+
+fn palette_index([red, green, blue]: [u8; 3]) -> u32 {
+    let level = |value: u8| (u32::from(value) * (SIXEL_LEVELS - 1) + 127) / 255;
+    (level(red) * SIXEL_LEVELS + level(green)) * SIXEL_LEVELS + level(blue)
+}
+
+/// Repeated characters are written as `!<count><char>`.
+fn push_run_length(output: &mut String, chars: impl Iterator<Item = char>) {
+    let mut chars = chars.peekable();
+    while let Some(char) = chars.next() {
+        let mut count = 1;
+        while chars.next_if_eq(&char).is_some() {
+            count += 1;
+        }
+        if count > 3 {
+            output.push_str(&format!("!{}{}", count, char));
+        } else {
+            for _ in 0..count {
+                output.push(char);
+            }
+        }
+    }
+}
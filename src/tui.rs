@@ -0,0 +1,675 @@
+//! Full-screen interactive display, with a calendar of all dates.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::num::NonZero;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use chrono::{Datelike as _, Months, NaiveDate};
+use everygarf::{DateUrl, ImageFormat, UrlPath};
+use futures::StreamExt as _;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Gauge, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use reqwest::{Client, Url};
+use tokio::sync::{mpsc, watch};
+
+use crate::cache::CacheData;
+use crate::controller::Sender;
+use crate::dates;
+use crate::download::{self, DownloadOptions};
+use crate::hooks::Hooks;
+use crate::journal::{Event as JournalEvent, Journal};
+use crate::progress::{describe_rate, describe_success, describe_warning};
+use crate::proxy::ProxyPool;
+use crate::state::{Goal, Stage, State, Update, UpdateSuccess, UpdateWarning};
+
+/// Time between redraws, and checks for key presses.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+/// Lines kept in activity log.
+const LOG_LENGTH: usize = 100;
+
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub struct Session {
+    pub pending_dates: Vec<DateUrl>,
+    pub existing_dates: Vec<NaiveDate>,
+    /// Latest comic found at source, which may be later than expected.
+    pub latest: NaiveDate,
+    pub cache_url: Option<UrlPath>,
+    pub should_ping: bool,
+    pub client_initial: Client,
+    pub client: Client,
+    pub directory: PathBuf,
+    pub job_count: NonZero<usize>,
+    pub max_attempts: NonZero<usize>,
+    pub image_format: ImageFormat,
+    pub proxies: ProxyPool,
+    pub hooks: Option<Arc<Hooks>>,
+    pub journal: Option<Journal>,
+}
+
+/// Progress of session, once user has quit.
+pub struct Outcome {
+    /// Complete if all requested dates were saved, otherwise interrupted.
+    pub state: State,
+    /// Dates requested at start or retried, in order.
+    pub dates: Vec<NaiveDate>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DateStatus {
+    Present,
+    Queued,
+    Active(Stage),
+    Failed,
+}
+
+/// Summary of all dates in a month, in order of display priority.
+#[derive(Clone, Copy, PartialEq)]
+enum MonthStatus {
+    Failed,
+    Active,
+    Queued,
+    Complete,
+    Partial,
+    Missing,
+}
+
+struct App {
+    state: State,
+    /// Dates without a status are missing, and were not requested.
+    statuses: HashMap<NaiveDate, DateStatus>,
+    errors: HashMap<NaiveDate, String>,
+    requested_dates: HashSet<NaiveDate>,
+    log: VecDeque<Line<'static>>,
+    selected: NaiveDate,
+    latest: NaiveDate,
+    paused: bool,
+    /// Prologue has finished, and dates are being downloaded.
+    started: bool,
+    should_quit: bool,
+}
+
+/// Run all pending downloads, until user quits.
+///
+/// Unlike other progress styles, failed dates do not stop other downloads, and can be retried.
+pub async fn run(session: Session) -> Result<Outcome> {
+    let mut terminal = ratatui::init();
+    let result = run_app(&mut terminal, session).await;
+    ratatui::restore();
+    result
+}
+
+async fn run_app(terminal: &mut DefaultTerminal, session: Session) -> Result<Outcome> {
+    let Session {
+        pending_dates,
+        existing_dates,
+        latest,
+        cache_url,
+        should_ping,
+        client_initial,
+        client,
+        directory,
+        job_count,
+        max_attempts,
+        image_format,
+        proxies,
+        hooks,
+        journal,
+    } = session;
+
+    let mut app = App::new(&existing_dates, &pending_dates, latest);
+
+    let (tx, mut rx) = mpsc::channel(job_count.into());
+    let tx = Sender::new(tx);
+    let (result_tx, mut result_rx) = mpsc::unbounded_channel::<(NaiveDate, Result<Url>)>();
+    let (retry_tx, retry_rx) = mpsc::unbounded_channel::<NaiveDate>();
+    let (pause_tx, pause_rx) = watch::channel(false);
+
+    let engine = async {
+        if should_ping
+            && download::check_proxies(&tx, &client_initial, &proxies)
+                .await
+                .is_err()
+        {
+            return;
+        }
+
+        let mut cache_data = CacheData::new();
+        if let Some(cache_url) = cache_url {
+            match download::fetch_cached_urls(&tx, &client_initial, cache_url).await {
+                Ok(data) => cache_data = data,
+                // Urls can still be resolved without cache
+                Err(error) => tx.send_error(error).await,
+            }
+        }
+        let cache_data = &cache_data;
+
+        let retried_dates = futures::stream::unfold(retry_rx, async |mut retry_rx| {
+            let date = retry_rx.recv().await?;
+            Some((
+                DateUrl {
+                    date,
+                    image_url: None,
+                },
+                retry_rx,
+            ))
+        });
+
+        futures::stream::iter(pending_dates)
+            .chain(retried_dates)
+            .map(|mut date_url| {
+                let tx = tx.clone();
+                let mut pause_rx = pause_rx.clone();
+                if date_url.image_url.is_none() {
+                    date_url.image_url = cache_data.get(&date_url.date).cloned();
+                }
+                let options = DownloadOptions {
+                    date_url,
+                    client: client.clone(),
+                    directory: &directory,
+                    max_attempts,
+                    image_format,
+                    proxies: &proxies,
+                    resolve_only: false,
+                    journal: journal.as_ref(),
+                    hooks: hooks.as_deref(),
+                };
+                async move {
+                    // Dates already started are not paused
+                    let _ = pause_rx.wait_for(|paused| !paused).await;
+                    let date = options.date_url.date;
+                    let journal = options.journal;
+                    let result = async {
+                        if let Some(journal) = journal {
+                            journal.record(JournalEvent::Started(date))?;
+                        }
+                        let image_url = download::download_image(&tx, options).await?;
+                        if let Some(journal) = journal {
+                            journal.record(JournalEvent::Done(date))?;
+                        }
+                        Ok(image_url)
+                    }
+                    .await;
+                    // Not published dates are left pending, to be tried by the next run
+                    if let Err(error) = &result
+                        && !download::is_not_published(error)
+                        && let Some(journal) = journal
+                    {
+                        // Already failing, so another error would not be useful
+                        let _ = journal.record(JournalEvent::Failed(date));
+                    }
+                    (date, result)
+                }
+            })
+            .buffer_unordered(job_count.into())
+            .for_each(async |result| {
+                let _ = result_tx.send(result);
+            })
+            .await;
+    };
+    let mut engine = Box::pin(engine);
+    let mut engine_done = false;
+
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+    while !app.should_quit {
+        tokio::select! {
+            () = &mut engine, if !engine_done => engine_done = true,
+            Some(message) = rx.recv() => match message {
+                Ok(update) => app.update(update),
+                Err(error) => app.log_error(None, &error),
+            },
+            Some((date, result)) = result_rx.recv() => app.finish_date(date, result),
+            _ = tick.tick() => {
+                while event::poll(Duration::ZERO).with_context(|| "reading terminal events")? {
+                    if let Event::Key(key) =
+                        event::read().with_context(|| "reading terminal events")?
+                        && key.kind == KeyEventKind::Press
+                    {
+                        for date in app.handle_key(key) {
+                            let _ = retry_tx.send(date);
+                        }
+                        pause_tx.send_replace(app.paused);
+                    }
+                }
+                terminal
+                    .draw(|frame| app.draw(frame))
+                    .with_context(|| "drawing to terminal")?;
+            }
+        }
+    }
+
+    // Dates left queued or in-flight are resumed by the next run
+    drop(engine);
+    if app.is_complete() {
+        if let Some(journal) = journal {
+            journal
+                .finish()
+                .with_context(|| "failed to remove journal")?;
+        }
+        app.state.update(Update::Success(UpdateSuccess::Complete));
+    } else {
+        app.state.set_interrupted();
+    }
+
+    let mut dates: Vec<_> = app.requested_dates.into_iter().collect();
+    dates.sort();
+    Ok(Outcome {
+        state: app.state,
+        dates,
+    })
+}
+
+impl App {
+    fn new(existing_dates: &[NaiveDate], pending_dates: &[DateUrl], latest: NaiveDate) -> Self {
+        let mut statuses = HashMap::new();
+        for date in existing_dates {
+            statuses.insert(*date, DateStatus::Present);
+        }
+        for date_url in pending_dates {
+            statuses.insert(date_url.date, DateStatus::Queued);
+        }
+
+        let selected = pending_dates
+            .first()
+            .map(|date_url| date_url.date)
+            .unwrap_or(latest);
+
+        Self {
+            state: State::new(pending_dates.len(), Goal::Save),
+            statuses,
+            errors: HashMap::new(),
+            requested_dates: pending_dates.iter().map(|date_url| date_url.date).collect(),
+            log: VecDeque::with_capacity(LOG_LENGTH),
+            selected,
+            latest,
+            paused: false,
+            started: false,
+            should_quit: false,
+        }
+    }
+
+    fn update(&mut self, update: Update) {
+        #[cfg(feature = "metrics")]
+        crate::metrics::record(update);
+        self.state.update(update);
+        match update {
+            Update::EnterStage { date, stage } => {
+                self.started = true;
+                self.statuses.insert(date, DateStatus::Active(stage));
+            }
+            Update::Success(success) => {
+                match success {
+                    UpdateSuccess::SaveImage { date } => {
+                        self.statuses.insert(date, DateStatus::Present);
+                        self.errors.remove(&date);
+                    }
+                    UpdateSuccess::ProxyPing | UpdateSuccess::FetchCache { .. } => {}
+                    // Too frequent to log
+                    _ => return,
+                }
+                self.push_log(Line::raw(describe_success(success)));
+            }
+            Update::Warning(warning) => {
+                self.push_log(Line::styled(
+                    format!("warning: {}", describe_warning(warning)),
+                    Color::Yellow,
+                ));
+            }
+        }
+    }
+
+    /// All requested dates were saved.
+    fn is_complete(&self) -> bool {
+        self.statuses
+            .values()
+            .all(|status| *status == DateStatus::Present)
+    }
+
+    fn finish_date(&mut self, date: NaiveDate, result: Result<Url>) {
+        // Shown as missing, so it can be retried once published
+        if let Err(error) = &result
+            && download::is_not_published(error)
+        {
+            self.statuses.remove(&date);
+            self.update(Update::Warning(UpdateWarning::NotPublished { date }));
+            return;
+        }
+        if let Err(error) = result {
+            self.state.remove_job(date);
+            self.statuses.insert(date, DateStatus::Failed);
+            self.log_error(Some(date), &error);
+            self.errors.insert(date, format!("{:#}", error));
+        }
+    }
+
+    fn log_error(&mut self, date: Option<NaiveDate>, error: &anyhow::Error) {
+        let message = match date {
+            Some(date) => format!("error: {} | {:#}", date, error),
+            None => format!("error: {:#}", error),
+        };
+        self.push_log(Line::styled(message, Color::Red));
+    }
+
+    fn push_log(&mut self, line: Line<'static>) {
+        if self.log.len() >= LOG_LENGTH {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    /// Returns dates to retry.
+    fn handle_key(&mut self, key: KeyEvent) -> Vec<NaiveDate> {
+        let selected = self.selected;
+        let moved = match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.should_quit = true;
+                None
+            }
+            KeyCode::Char('q') | KeyCode::Esc => {
+                self.should_quit = true;
+                None
+            }
+            KeyCode::Char(' ') | KeyCode::Char('p') => {
+                self.paused = !self.paused;
+                None
+            }
+            KeyCode::Char('r') => return self.retry(vec![selected]),
+            KeyCode::Char('R') => {
+                let failed = dates::date_iter(month_start(selected)..=month_end(selected))
+                    .filter(|date| self.statuses.get(date) == Some(&DateStatus::Failed))
+                    .collect();
+                return self.retry(failed);
+            }
+            KeyCode::Left | KeyCode::Char('h') => selected.pred_opt(),
+            KeyCode::Right | KeyCode::Char('l') => selected.succ_opt(),
+            KeyCode::Up | KeyCode::Char('k') => selected.checked_sub_days(chrono::Days::new(7)),
+            KeyCode::Down | KeyCode::Char('j') => selected.checked_add_days(chrono::Days::new(7)),
+            KeyCode::PageUp | KeyCode::Char('[') => selected.checked_sub_months(Months::new(1)),
+            KeyCode::PageDown | KeyCode::Char(']') => selected.checked_add_months(Months::new(1)),
+            KeyCode::Char('{') => selected.checked_sub_months(Months::new(12)),
+            KeyCode::Char('}') => selected.checked_add_months(Months::new(12)),
+            KeyCode::Home | KeyCode::Char('g') => Some(dates::FIRST_DATE),
+            KeyCode::End | KeyCode::Char('G') => Some(self.latest),
+            _ => None,
+        };
+        if let Some(date) = moved {
+            self.selected = date.clamp(dates::FIRST_DATE, self.latest);
+        }
+        Vec::new()
+    }
+
+    /// Queue dates which are missing or failed, returning those queued.
+    fn retry(&mut self, dates: Vec<NaiveDate>) -> Vec<NaiveDate> {
+        let dates: Vec<_> = dates
+            .into_iter()
+            .filter(|date| {
+                matches!(self.statuses.get(date), None | Some(DateStatus::Failed))
+                    && (dates::FIRST_DATE..=self.latest).contains(date)
+            })
+            .collect();
+        for date in &dates {
+            self.statuses.insert(*date, DateStatus::Queued);
+            self.requested_dates.insert(*date);
+        }
+        self.state.add_units(dates.len());
+        dates
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header_area, body_area, log_area, help_area] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Min(12),
+            Constraint::Length(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [years_area, month_area] =
+            Layout::horizontal([Constraint::Length(45), Constraint::Min(30)]).areas(body_area);
+
+        self.draw_header(frame, header_area);
+        self.draw_years(frame, years_area);
+        self.draw_month(frame, month_area);
+
+        // Newest at bottom
+        let log_height = log_area.height.saturating_sub(2) as usize;
+        let log: Vec<_> = self
+            .log
+            .iter()
+            .skip(self.log.len().saturating_sub(log_height))
+            .cloned()
+            .collect();
+        frame.render_widget(
+            Paragraph::new(log).block(Block::bordered().title(" Activity ")),
+            log_area,
+        );
+
+        frame.render_widget(
+            Paragraph::new(
+                " arrows: move | [ ]: month | { }: year | space: pause | r: retry date | R: retry failed in month | q: quit",
+            )
+            .style(Style::new().fg(Color::DarkGray)),
+            help_area,
+        );
+    }
+
+    fn draw_header(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" everygarf ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let [gauge_area, stats_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Length(1)]).areas(inner);
+
+        let ratio = (self.state.percent() / 100.0).clamp(0.0, 1.0);
+        let label = format!(
+            "{}/{} ({:.2}%)",
+            self.state.completed_units(),
+            self.state.total_units(),
+            self.state.percent(),
+        );
+        frame.render_widget(
+            Gauge::default()
+                .ratio(ratio.into())
+                .label(label)
+                .gauge_style(Color::Green),
+            gauge_area,
+        );
+
+        let status = if self.paused {
+            "paused"
+        } else if !self.started {
+            "starting"
+        } else if self
+            .statuses
+            .values()
+            .any(|status| matches!(status, DateStatus::Queued | DateStatus::Active(_)))
+        {
+            "downloading"
+        } else {
+            "idle"
+        };
+        let failed = self.errors.len();
+        let stats = format!(
+            " {} | jobs: {} resolving, {} downloading, {} saving | failed: {} | {}",
+            status,
+            self.state.stage_count(Stage::ResolveUrl),
+            self.state.stage_count(Stage::FetchImage),
+            self.state.stage_count(Stage::SaveImage),
+            failed,
+            describe_rate(&self.state),
+        );
+        frame.render_widget(Paragraph::new(stats), stats_area);
+    }
+
+    /// Heatmap of months, one row per year.
+    fn draw_years(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Years ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let mut lines = vec![Line::raw(format!(
+            "      {}",
+            MONTH_NAMES.map(|name| &name[..2]).join(" ")
+        ))];
+
+        // Keep selected year in view
+        let first_year = dates::FIRST_DATE.year();
+        let last_year = self.latest.year();
+        let height = i32::from(inner.height.saturating_sub(1));
+        let start_year = (self.selected.year() - height / 2)
+            .min(last_year - height + 1)
+            .max(first_year);
+
+        for year in (start_year..=last_year).take(height as usize) {
+            let mut spans = vec![Span::raw(format!("{} ", year))];
+            for month in 1..=12 {
+                let cell = match NaiveDate::from_ymd_opt(year, month, 1)
+                    .and_then(|date| self.month_status(date))
+                {
+                    Some(status) => {
+                        let mut style = Style::new().fg(month_color(status));
+                        if self.selected.year() == year && self.selected.month() == month {
+                            style = style.add_modifier(Modifier::REVERSED);
+                        }
+                        Span::styled("██", style)
+                    }
+                    None => Span::raw("  "),
+                };
+                spans.push(Span::raw(" "));
+                spans.push(cell);
+            }
+            lines.push(Line::from(spans));
+        }
+
+        frame.render_widget(Paragraph::new(lines), inner);
+    }
+
+    /// Calendar of selected month, and details of selected date.
+    fn draw_month(&self, frame: &mut Frame, area: Rect) {
+        let title = format!(
+            " {} {} ",
+            MONTH_NAMES[self.selected.month0() as usize],
+            self.selected.year(),
+        );
+        let block = Block::bordered().title(title);
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let mut lines = vec![Line::raw(" Mo Tu We Th Fr Sa Su")];
+        let first = month_start(self.selected);
+        let mut spans = vec![Span::raw(
+            "   ".repeat(first.weekday().num_days_from_monday() as usize),
+        )];
+        for date in dates::date_iter(first..=month_end(self.selected)) {
+            let mut style = Style::new().fg(self.date_color(date));
+            if date == self.selected {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            spans.push(Span::raw(" "));
+            spans.push(Span::styled(format!("{:2}", date.day()), style));
+            if date.weekday() == chrono::Weekday::Sun {
+                lines.push(Line::from(std::mem::take(&mut spans)));
+            }
+        }
+        if !spans.is_empty() {
+            lines.push(Line::from(spans));
+        }
+
+        lines.push(Line::raw(""));
+        let description = match self.statuses.get(&self.selected) {
+            _ if !(dates::FIRST_DATE..=self.latest).contains(&self.selected) => "unavailable",
+            None => "missing",
+            Some(DateStatus::Present) => "saved",
+            Some(DateStatus::Queued) => "queued",
+            Some(DateStatus::Active(Stage::ResolveUrl)) => "resolving url",
+            Some(DateStatus::Active(Stage::FetchImage)) => "downloading",
+            Some(DateStatus::Active(Stage::SaveImage)) => "saving",
+            Some(DateStatus::Failed) => "failed",
+        };
+        lines.push(Line::raw(format!(" {}: {}", self.selected, description)));
+        if let Some(error) = self.errors.get(&self.selected) {
+            lines.push(Line::styled(format!(" {}", error), Color::Red));
+        }
+
+        frame.render_widget(Paragraph::new(lines), inner);
+    }
+
+    /// Returns `None` if no dates of month are available.
+    fn month_status(&self, date: NaiveDate) -> Option<MonthStatus> {
+        let start = month_start(date).max(dates::FIRST_DATE);
+        let end = month_end(date).min(self.latest);
+        if start > end {
+            return None;
+        }
+
+        let mut present = 0;
+        let mut total = 0;
+        let mut status = MonthStatus::Missing;
+        for date in dates::date_iter(start..=end) {
+            total += 1;
+            let date_status = match self.statuses.get(&date) {
+                None => continue,
+                Some(DateStatus::Present) => {
+                    present += 1;
+                    continue;
+                }
+                Some(DateStatus::Failed) => MonthStatus::Failed,
+                Some(DateStatus::Active(_)) => MonthStatus::Active,
+                Some(DateStatus::Queued) => MonthStatus::Queued,
+            };
+            // Variants are ordered by priority
+            if (date_status as u8) < (status as u8) {
+                status = date_status;
+            }
+        }
+
+        Some(match status {
+            MonthStatus::Missing if present == total => MonthStatus::Complete,
+            MonthStatus::Missing if present > 0 => MonthStatus::Partial,
+            status => status,
+        })
+    }
+
+    fn date_color(&self, date: NaiveDate) -> Color {
+        if !(dates::FIRST_DATE..=self.latest).contains(&date) {
+            return Color::Black;
+        }
+        match self.statuses.get(&date) {
+            None => Color::DarkGray,
+            Some(DateStatus::Present) => Color::Green,
+            Some(DateStatus::Queued) => Color::Blue,
+            Some(DateStatus::Active(_)) => Color::Yellow,
+            Some(DateStatus::Failed) => Color::Red,
+        }
+    }
+}
+
+fn month_color(status: MonthStatus) -> Color {
+    match status {
+        MonthStatus::Failed => Color::Red,
+        MonthStatus::Active => Color::Yellow,
+        MonthStatus::Queued => Color::Blue,
+        MonthStatus::Complete => Color::Green,
+        MonthStatus::Partial => Color::Cyan,
+        MonthStatus::Missing => Color::DarkGray,
+    }
+}
+
+fn month_start(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).expect("First day of month should exist")
+}
+
+fn month_end(date: NaiveDate) -> NaiveDate {
+    let next_month = month_start(date) + Months::new(1);
+    next_month
+        .pred_opt()
+        .expect("Last day of month should exist")
+}
@@ -0,0 +1,174 @@
+//! Long-running mode, which downloads each new comic once it is published.
+
+use std::num::NonZero;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use chrono::{Local, NaiveDate, Utc};
+use everygarf::DateUrl;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use crate::args::{Args, defaults};
+use crate::controller::Sender;
+use crate::download::{self, DownloadOptions};
+use crate::hooks::Hooks;
+use crate::io::{create_target_directory, get_target_directory};
+use crate::progress::{Verbosity, describe_warning};
+use crate::shutdown::Shutdown;
+use crate::state::{Update, UpdateWarning};
+use crate::{build_client, dates, get_existing_dates};
+
+/// Gives up on a date if it is not available `give_up_after` hours after its expected publish
+/// time.
+pub fn run(directory: Option<PathBuf>, give_up_after: NonZero<u32>, args: &Args) -> Result<()> {
+    let directory = match directory.or_else(|| args.directory.clone()) {
+        Some(directory) => directory,
+        None => get_target_directory()
+            .with_context(|| "failed to find appropriate target directory path")?,
+    };
+    create_target_directory(&directory, false)
+        .with_context(|| "failed to create target directory")?;
+
+    let client = build_client(args, Duration::from_secs(args.timeout_primary.into()))?;
+    let proxies = args.proxy_pool();
+    let verbosity = args.verbosity();
+    let hooks = Hooks::new(args.on_save.clone(), Vec::new(), &directory);
+    let give_up_after = chrono::Duration::hours(give_up_after.get().into());
+
+    Runtime::new().unwrap().block_on(async move {
+        let shutdown = Shutdown::listen();
+
+        // Updates are only used to report warnings
+        let (tx, mut rx) = mpsc::channel(args.job_count.into());
+        let tx = Sender::new(tx);
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                #[cfg(feature = "metrics")]
+                if let Ok(update) = message {
+                    crate::metrics::record(update);
+                }
+                // Failed hooks are not retried, so are always reported
+                if let Ok(Update::Warning(warning)) = message
+                    && (verbosity == Verbosity::Verbose
+                        || matches!(warning, UpdateWarning::HookFailed { .. }))
+                {
+                    log(verbosity, describe_warning(warning));
+                }
+            }
+        });
+
+        // Start with latest comic, if it was not already downloaded
+        let latest = dates::latest();
+        let existing_dates = get_existing_dates(&directory)?;
+        #[cfg(feature = "metrics")]
+        if let Some(&date) = existing_dates.iter().max() {
+            crate::metrics::record_existing_date(date);
+        }
+        let mut date = if existing_dates.contains(&latest) {
+            next_date(latest)
+        } else {
+            latest
+        };
+
+        'watch: loop {
+            let publish_time = dates::publish_time(date);
+            if let Ok(wait) = (publish_time - Utc::now()).to_std() {
+                log(
+                    verbosity,
+                    format!(
+                        "waiting for {} (expected at {}).",
+                        date,
+                        publish_time.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+                    ),
+                );
+                if !sleep_unless_stopped(wait, &shutdown).await {
+                    break 'watch;
+                }
+            }
+
+            // Poll with backoff, until comic is published
+            let mut delay = defaults::WATCH_POLL_MIN;
+            let is_saved = loop {
+                let date_url = DateUrl {
+                    date,
+                    image_url: None,
+                };
+                let options = DownloadOptions {
+                    date_url,
+                    client: client.clone(),
+                    directory: &directory,
+                    // Failed attempts are repeated by polling
+                    max_attempts: NonZero::<usize>::MIN,
+                    image_format: args.image_format,
+                    proxies: &proxies,
+                    resolve_only: false,
+                    journal: None,
+                    hooks: hooks.as_ref(),
+                };
+                let error = match download::download_image(&tx, options).await {
+                    Ok(_) => break true,
+                    Err(error) => error,
+                };
+
+                if Utc::now() - publish_time > give_up_after {
+                    log_error(format!("{} | giving up: {:#}", date, error));
+                    break false;
+                }
+                if download::is_not_published(&error) {
+                    if verbosity == Verbosity::Verbose {
+                        log(verbosity, format!("{} | not published yet.", date));
+                    }
+                } else {
+                    log(
+                        verbosity,
+                        format!("{} | failed, will retry: {:#}", date, error),
+                    );
+                }
+                if !sleep_unless_stopped(delay, &shutdown).await {
+                    break 'watch;
+                }
+                delay = (delay * 2).min(defaults::WATCH_POLL_MAX);
+            };
+
+            if is_saved {
+                log(verbosity, format!("{} | saved image.", date));
+            }
+            if shutdown.is_requested() {
+                break 'watch;
+            }
+            date = next_date(date);
+        }
+
+        log(verbosity, "stopped.".to_string());
+        Ok(())
+    })
+}
+
+fn next_date(date: NaiveDate) -> NaiveDate {
+    date + chrono::Duration::days(1)
+}
+
+/// Returns `false` if shutdown was requested instead.
+async fn sleep_unless_stopped(duration: Duration, shutdown: &Shutdown) -> bool {
+    tokio::select! {
+        () = tokio::time::sleep(duration) => true,
+        () = shutdown.requested() => false,
+    }
+}
+
+fn log(verbosity: Verbosity, message: String) {
+    if verbosity > Verbosity::Quiet {
+        println!("{}", timestamped(message));
+    }
+}
+
+/// Printed to stderr, even if quiet.
+fn log_error(message: String) {
+    eprintln!("{}", timestamped(message));
+}
+
+fn timestamped(message: String) -> String {
+    format!("{} | {}", Local::now().format("%Y-%m-%d %H:%M:%S"), message)
+}
@@ -0,0 +1,303 @@
+//! Summary of a finished run, posted as JSON to user-provided urls.
+//!
+//! The summary message is repeated under `text`, `content`, and `body`, which are read by Slack,
+//! Discord, and Matrix (hookshot) respectively, so the same payload fits each of them.
+
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use chrono::NaiveDate;
+use reqwest::{Client, Url};
+use serde::Serialize;
+
+use crate::progress::format_duration;
+use crate::state::{Goal, State, Status};
+
+/// Delay before first retry, doubled for each following retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_ATTEMPTS: u32 = 4;
+/// Failed dates listed in summary message; all are still included in `failed_dates`.
+const MESSAGE_DATE_COUNT: usize = 5;
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    text: &'a str,
+    content: &'a str,
+    body: &'a str,
+    status: Status,
+    completed: usize,
+    total: usize,
+    warnings: usize,
+    failed_dates: &'a [NaiveDate],
+    downloaded_bytes: u64,
+    duration_secs: f64,
+    error: Option<String>,
+}
+
+/// Post summary to each url, reporting failures without returning them.
+pub async fn send_all(client: &Client, urls: &[Url], state: &State, error: Option<&anyhow::Error>) {
+    let message = describe_run(state, error);
+    let payload = Payload {
+        text: &message,
+        content: &message,
+        body: &message,
+        status: state.status(),
+        completed: state.completed_units(),
+        total: state.total_units(),
+        warnings: state.warning_count(),
+        failed_dates: state.failed_dates(),
+        downloaded_bytes: state.downloaded_bytes(),
+        duration_secs: state.elapsed().as_secs_f64(),
+        error: error.map(|error| format!("{:#}", error)),
+    };
+    for url in urls {
+        if let Err(error) = send(client, url, &payload, RETRY_DELAY).await {
+            eprintln!("warning: webhook {}: {:#}", url, error);
+        }
+    }
+}
+
+/// Only server errors and failed requests are retried, as client errors would not change.
+async fn send(
+    client: &Client,
+    url: &Url,
+    payload: &Payload<'_>,
+    retry_delay: Duration,
+) -> Result<()> {
+    let mut delay = retry_delay;
+    let mut attempt = 1;
+    loop {
+        let (result, is_retryable) = match client.post(url.clone()).json(payload).send().await {
+            Ok(response) => {
+                let status = response.status();
+                let result = response
+                    .error_for_status()
+                    .map(drop)
+                    .with_context(|| "server returned error");
+                (result, status.is_server_error())
+            }
+            Err(error) => (Err(error).with_context(|| "sending request"), true),
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(error) if !is_retryable => return Err(error),
+            Err(error) if attempt >= MAX_ATTEMPTS => {
+                return Err(error.context(format!("failed after {} attempts", attempt)));
+            }
+            Err(_) => (),
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+fn describe_run(state: &State, error: Option<&anyhow::Error>) -> String {
+    let verb = match state.goal() {
+        Goal::Resolve => "resolved",
+        Goal::Save => "saved",
+    };
+    let outcome = match state.status() {
+        Status::Complete if error.is_none() => "complete",
+        Status::Interrupted => "interrupted",
+        _ => "failed",
+    };
+    let mut message = format!(
+        "everygarf {}: {} {}/{} comics in {}",
+        outcome,
+        verb,
+        state.completed_units(),
+        state.total_units(),
+        format_duration(state.elapsed()),
+    );
+
+    let failed_dates = state.failed_dates();
+    if !failed_dates.is_empty() {
+        let dates: Vec<_> = failed_dates
+            .iter()
+            .take(MESSAGE_DATE_COUNT)
+            .map(NaiveDate::to_string)
+            .collect();
+        message += &format!("\nfailed dates: {}", dates.join(", "));
+        if failed_dates.len() > MESSAGE_DATE_COUNT {
+            message += &format!(" (and {} more)", failed_dates.len() - MESSAGE_DATE_COUNT);
+        }
+    }
+    if let Some(error) = error {
+        message += &format!("\nerror: {:#}", error);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::state::{DateContext, Update, UpdateSuccess, UpdateWarning};
+
+    const TEST_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+    /// Local server which responds with each status in turn, repeating the last one.
+    /// Returns url and bodies of all requests received.
+    async fn start_server(statuses: &[u16]) -> (Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let statuses = statuses.to_vec();
+
+        let server_bodies = bodies.clone();
+        tokio::spawn(async move {
+            for index in 0.. {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let body = read_request_body(&mut stream).await;
+                server_bodies.lock().unwrap().push(body);
+                let status = statuses[index.min(statuses.len() - 1)];
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, bodies)
+    }
+
+    async fn read_request_body(stream: &mut tokio::net::TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            let count = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..count]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(header_end) = text.find("\r\n\r\n") {
+                let length: usize = text[..header_end]
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse().unwrap())
+                    })
+                    .unwrap_or(0);
+                let body_start = header_end + 4;
+                if request.len() >= body_start + length {
+                    return String::from_utf8(request[body_start..body_start + length].to_vec())
+                        .unwrap();
+                }
+            }
+            if count == 0 {
+                panic!("connection closed before full request");
+            }
+        }
+    }
+
+    fn failed_state() -> (State, anyhow::Error) {
+        let date = NaiveDate::from_ymd_opt(2000, 1, 2).unwrap();
+        let mut state = State::new(2, Goal::Save);
+        state.update(Update::Success(UpdateSuccess::SaveImage {
+            date: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+        }));
+        state.update(Update::Warning(UpdateWarning::Failed { date }));
+        let error = anyhow::anyhow!("connection reset").context(DateContext(date));
+        state.set_failed(&error);
+        (state, error)
+    }
+
+    fn payload<'a>(state: &'a State, message: &'a str, error: &anyhow::Error) -> Payload<'a> {
+        Payload {
+            text: message,
+            content: message,
+            body: message,
+            status: state.status(),
+            completed: state.completed_units(),
+            total: state.total_units(),
+            warnings: state.warning_count(),
+            failed_dates: state.failed_dates(),
+            downloaded_bytes: state.downloaded_bytes(),
+            duration_secs: 0.0,
+            error: Some(format!("{:#}", error)),
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_summary_payload() {
+        let (url, bodies) = start_server(&[200]).await;
+        let (state, error) = failed_state();
+        send_all(&Client::new(), &[url], &state, Some(&error)).await;
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 1);
+        let json: serde_json::Value = serde_json::from_str(&bodies[0]).unwrap();
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["completed"], 1);
+        assert_eq!(json["total"], 2);
+        assert_eq!(json["failed_dates"], serde_json::json!(["2000-01-02"]));
+        assert_eq!(json["error"], "2000-01-02: connection reset");
+        let message = json["text"].as_str().unwrap();
+        assert!(message.starts_with("everygarf failed: saved 1/2 comics"));
+        assert!(message.contains("failed dates: 2000-01-02"));
+        assert_eq!(json["content"], json["text"]);
+        assert_eq!(json["body"], json["text"]);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let (url, bodies) = start_server(&[500, 503, 200]).await;
+        let (state, error) = failed_state();
+        let message = describe_run(&state, Some(&error));
+        let payload = payload(&state, &message, &error);
+        send(&Client::new(), &url, &payload, TEST_RETRY_DELAY)
+            .await
+            .unwrap();
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 3);
+        assert!(bodies.iter().all(|body| body == &bodies[0]));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (url, bodies) = start_server(&[502]).await;
+        let (state, error) = failed_state();
+        let message = describe_run(&state, Some(&error));
+        let payload = payload(&state, &message, &error);
+        let result = send(&Client::new(), &url, &payload, TEST_RETRY_DELAY).await;
+        assert!(result.is_err());
+        assert_eq!(bodies.lock().unwrap().len(), MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        for status in [400, 401, 404] {
+            let (url, bodies) = start_server(&[status, 200]).await;
+            let (state, error) = failed_state();
+            let message = describe_run(&state, Some(&error));
+            let payload = payload(&state, &message, &error);
+            let result = send(&Client::new(), &url, &payload, TEST_RETRY_DELAY).await;
+            assert!(result.is_err());
+            assert_eq!(bodies.lock().unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_failed_requests() {
+        // Nothing is listening once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+        let (state, error) = failed_state();
+        let message = describe_run(&state, Some(&error));
+        let payload = payload(&state, &message, &error);
+        let error = send(&Client::new(), &url, &payload, TEST_RETRY_DELAY)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("{} attempts", MAX_ATTEMPTS))
+        );
+    }
+}